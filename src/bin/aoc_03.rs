@@ -1,101 +1,142 @@
 //! AoC 2021 - 03
 
-use std::env;
+use std::{env, error::Error};
 
-fn parse_file(s: &str) -> Vec<u16> {
-    s.lines()
-        .map(|l| u16::from_str_radix(l, 2).unwrap())
-        .collect()
+/// Diagnostic report numbers and how many bits each has, up to 128
+struct Report {
+    width: usize,
+    nums: Vec<u128>,
 }
 
-fn most_common_bit_pattern(nums: &[u16], bit_depth: usize, tie: u16) -> u16 {
-    let mut result_bits = vec![0; bit_depth];
-    for i in 0..bit_depth {
-        let mut diff_count = 0;
-        for n in nums {
-            let n_shift = n >> i;
-            if n_shift & 0x1 == 0 {
-                diff_count -= 1;
-            } else {
-                diff_count += 1;
-            }
+impl Report {
+    /// All ones across the report's width
+    fn mask(&self) -> u128 {
+        u128::MAX >> (128 - self.width)
+    }
+}
+
+fn parse_file(s: &str) -> Result<Report, Box<dyn Error>> {
+    let lines = s
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>();
+    let width = lines.first().ok_or("empty report")?.len();
+    if width > 128 {
+        Err(format!(
+            "{} bit numbers are wider than the 128 supported",
+            width
+        ))?
+    }
+
+    let mut nums = Vec::new();
+    for (i, l) in lines.iter().enumerate() {
+        if l.len() != width {
+            Err(format!(
+                "line {} has {} bits, expected {}",
+                i + 1,
+                l.len(),
+                width
+            ))?
         }
-        result_bits[bit_depth - 1 - i] = if diff_count < 0 {
-            0
-        } else if diff_count > 0 {
-            1
-        } else {
-            tie
-        };
+        if !l.bytes().all(|b| b == b'0' || b == b'1') {
+            Err(format!("line {} isn't binary", i + 1))?
+        }
+        nums.push(u128::from_str_radix(l, 2)?);
     }
 
-    u16::from_str_radix(
-        &result_bits
-            .iter()
-            .map(|x| x.to_string())
-            .fold(String::new(), |mut acc, x| {
-                acc.push_str(&x);
-                acc
-            }),
-        2,
-    )
-    .unwrap()
+    Ok(Report { width, nums })
 }
 
-fn p1(nums: &[u16], bit_depth: usize) -> (u64, u64) {
-    let gamma = most_common_bit_pattern(nums, bit_depth, 0);
+fn most_common_bit_pattern(report: &Report, tie: u128) -> u128 {
+    (0..report.width).fold(0, |pattern, i| {
+        let ones = report.nums.iter().filter(|&&n| (n >> i) & 1 == 1).count();
+        let zeros = report.nums.len() - ones;
+        let bit = match ones.cmp(&zeros) {
+            std::cmp::Ordering::Less => 0,
+            std::cmp::Ordering::Greater => 1,
+            std::cmp::Ordering::Equal => tie,
+        };
+        pattern | bit << i
+    })
+}
 
-    // Negate but zero out the hiigher order bits
-    let epsilon = (!gamma << (16 - bit_depth)) >> (16 - bit_depth);
+fn p1(report: &Report) -> (u128, u128) {
+    let gamma = most_common_bit_pattern(report, 0);
+    let epsilon = !gamma & report.mask();
 
-    (gamma as u64, epsilon as u64)
+    (gamma, epsilon)
 }
 
-fn p2(nums: &[u16], bit_depth: usize) -> (u64, u64) {
-    let mut o2_nums = nums.to_vec();
-    o2_nums.sort();
-    let mut co2_nums = o2_nums.clone();
-    for i in (0..bit_depth).rev() {
-        let mut diff_count = 0;
-        for o2 in o2_nums.iter() {
-            if (o2 >> i) & 0x1 == 0 {
-                diff_count -= 1;
-            } else {
-                diff_count += 1;
+/// Binary trie of the report, most significant bit first, counting the numbers under each node
+struct BitTrie {
+    width: usize,
+    /// Children by bit, node 0 is the root
+    children: Vec<[Option<usize>; 2]>,
+    counts: Vec<usize>,
+}
+
+impl BitTrie {
+    fn new(report: &Report) -> Self {
+        let mut trie = Self {
+            width: report.width,
+            children: vec![[None; 2]],
+            counts: vec![0],
+        };
+        for &n in report.nums.iter() {
+            let mut node = 0;
+            trie.counts[node] += 1;
+            for i in (0..report.width).rev() {
+                let bit = ((n >> i) & 1) as usize;
+                node = match trie.children[node][bit] {
+                    Some(child) => child,
+                    None => {
+                        trie.children.push([None; 2]);
+                        trie.counts.push(0);
+                        let child = trie.children.len() - 1;
+                        trie.children[node][bit] = Some(child);
+                        child
+                    }
+                };
+                trie.counts[node] += 1;
             }
         }
-        let o2_matcher = if diff_count >= 0 { 1 } else { 0 };
-        if o2_nums.len() > 1 {
-            o2_nums.retain(|n| {
-                let bit = (n >> i) & 0x1;
-                bit == o2_matcher
-            });
-        }
+        trie
+    }
 
-        let mut diff_count = 0;
-        for co2 in co2_nums.iter() {
-            if (co2 >> i) & 0x1 == 0 {
-                diff_count -= 1;
-            } else {
-                diff_count += 1;
-            }
+    /// Follow the trie from the root, at each bit keeping the numbers with the more (or fewer)
+    /// common value, ties going to 1 (or 0). Once a single number is left its bits are followed
+    /// as they are.
+    fn walk(&self, most_common: bool) -> Option<u128> {
+        if self.counts[0] == 0 {
+            return None;
         }
-        let co2_matcher = if diff_count >= 0 { 0 } else { 1 };
-        if co2_nums.len() > 1 {
-            co2_nums.retain(|n| {
-                let bit = (n >> i) & 0x1;
-                bit == co2_matcher
-            });
+        let mut node = 0;
+        let mut value = 0;
+        for _ in 0..self.width {
+            let count = |bit: usize| self.children[node][bit].map_or(0, |c| self.counts[c]);
+            let (zeros, ones) = (count(0), count(1));
+            let bit = if zeros == 0 || ones == 0 {
+                (zeros == 0) as usize
+            } else if most_common {
+                (ones >= zeros) as usize
+            } else {
+                (ones < zeros) as usize
+            };
+            node = self.children[node][bit].unwrap();
+            value = value << 1 | bit as u128;
         }
+        Some(value)
     }
-    if o2_nums.len() != 1 {
-        panic!("[o2] we went through our bits without narrowing down to one");
-    }
-    if co2_nums.len() != 1 {
-        panic!("[o2] we went through our bits without narrowing down to one");
-    }
+}
 
-    (o2_nums[0] as u64, co2_nums[0] as u64)
+/// Oxygen generator and CO2 scrubber ratings
+fn p2(report: &Report) -> (u128, u128) {
+    let trie = BitTrie::new(report);
+    let o2 = trie.walk(true).expect("no numbers to rate");
+    let co2 = trie.walk(false).expect("no numbers to rate");
+
+    (o2, co2)
 }
 
 fn main() {
@@ -106,19 +147,42 @@ fn main() {
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
 
-    let nums = parse_file(&file_str);
-    let (p1_gam, p1_eps) = p1(&nums, 12);
-    let p1_prod = p1_gam * p1_eps;
+    let report = parse_file(&file_str).unwrap_or_else(|e| panic!("{}", e));
+    let (p1_gam, p1_eps) = p1(&report);
+    let p1_prod = p1_gam.checked_mul(p1_eps).expect("p1 overflows a u128");
     println!("p1: {}", p1_prod);
-    let (p2_gam, p2_eps) = p2(&nums, 12);
-    let p2_prod = p2_gam * p2_eps;
+    let (p2_o2, p2_co2) = p2(&report);
+    let p2_prod = p2_o2.checked_mul(p2_co2).expect("p2 overflows a u128");
     println!("p2: {}", p2_prod);
 }
 
+#[cfg(test)]
+#[path = "common/rng.rs"]
+mod rng;
+
 #[cfg(test)]
 mod test_day3 {
+    use super::rng::XorShift64;
     use super::*;
 
+    /// Filters the list one bit at a time
+    fn filter_ratings(report: &Report) -> (u128, u128) {
+        let rating = |most_common: bool| {
+            let mut nums = report.nums.clone();
+            for i in (0..report.width).rev() {
+                let ones = nums.iter().filter(|&&n| (n >> i) & 1 == 1).count();
+                // Every number left has the same bit here, so nothing to filter
+                if ones == 0 || ones == nums.len() {
+                    continue;
+                }
+                let keep = (ones * 2 >= nums.len()) == most_common;
+                nums.retain(|&n| ((n >> i) & 1 == 1) == keep);
+            }
+            nums[0]
+        };
+        (rating(true), rating(false))
+    }
+
     #[test]
     fn test() {
         let example = r#"00100
@@ -134,12 +198,41 @@ mod test_day3 {
 00010
 01010"#;
 
-        let nums = parse_file(example);
-        let (gam, eps) = p1(&nums, 5);
+        let report = parse_file(example).unwrap();
+        assert_eq!(report.width, 5);
+        let (gam, eps) = p1(&report);
         assert_eq!(gam, 22);
         assert_eq!(eps, 9);
-        let (o2, co2) = p2(&nums, 5);
+        let (o2, co2) = p2(&report);
         assert_eq!(o2, 23);
         assert_eq!(co2, 10);
+        assert_eq!(filter_ratings(&report), (23, 10));
+    }
+
+    #[test]
+    fn test_widths() {
+        let mut rng = XorShift64::new(0x6a09e667f3bcc909);
+
+        for &width in [1, 7, 64, 100, 128].iter() {
+            for _ in 0..20 {
+                let lines = (0..1 + rng.below(200))
+                    .map(|_| {
+                        (0..width)
+                            .map(|_| if rng.below(3) == 0 { '1' } else { '0' })
+                            .collect::<String>()
+                    })
+                    .collect::<Vec<_>>();
+                let report = parse_file(&lines.join("\n")).unwrap();
+                assert_eq!(report.width, width);
+                let (gam, eps) = p1(&report);
+                assert_eq!(gam ^ eps, report.mask());
+                assert_eq!(p2(&report), filter_ratings(&report));
+            }
+        }
+
+        assert!(parse_file("").is_err());
+        assert!(parse_file("0101\n011\n").is_err());
+        assert!(parse_file("0121\n").is_err());
+        assert!(parse_file(&"1".repeat(129)).is_err());
     }
 }
@@ -1,132 +1,315 @@
 //! AoC 2021 - 04
 
-use std::{collections::HashMap, collections::HashSet, env};
-
-static BINGOS: [[(usize, usize); 5]; 10/*12*/] = [
-    //[(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)], // diag
-    //[(4, 0), (3, 1), (2, 2), (1, 3), (0, 4)], // diag
-    [(0, 0), (0, 1), (0, 2), (0, 3), (0, 4)], // cols
-    [(1, 0), (1, 1), (1, 2), (1, 3), (1, 4)],
-    [(2, 0), (2, 1), (2, 2), (2, 3), (2, 4)],
-    [(3, 0), (3, 1), (3, 2), (3, 3), (3, 4)],
-    [(4, 0), (4, 1), (4, 2), (4, 3), (4, 4)],
-    [(0, 0), (1, 0), (2, 0), (3, 0), (4, 0)], // rows
-    [(0, 1), (1, 1), (2, 1), (3, 1), (4, 1)],
-    [(0, 2), (1, 2), (2, 2), (3, 2), (4, 2)],
-    [(0, 3), (1, 3), (2, 3), (3, 3), (4, 3)],
-    [(0, 4), (1, 4), (2, 4), (3, 4), (4, 4)],
-];
-
-fn check_for_bingo(map: &HashSet<(usize, usize)>) -> bool {
-    BINGOS.iter().any(|b| b.iter().all(|rc| map.contains(rc)))
-}
-
-fn score_board(board: &HashMap<usize, (usize, usize)>, marked: &HashSet<(usize, usize)>) -> usize {
-    board
-        .iter()
-        .filter_map(|(v, (r, c))| {
-            if !marked.contains(&(*r, *c)) {
-                Some(*v)
-            } else {
-                None
+use std::{collections::HashMap, env, error::Error, str::FromStr};
+
+/// Ways a board can win
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WinRule {
+    Rows,
+    Columns,
+    /// Either diagonal
+    Diagonals,
+    FourCorners,
+    FullCard,
+}
+
+impl FromStr for WinRule {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rows" => Ok(WinRule::Rows),
+            "columns" => Ok(WinRule::Columns),
+            "diagonals" => Ok(WinRule::Diagonals),
+            "corners" => Ok(WinRule::FourCorners),
+            "full" => Ok(WinRule::FullCard),
+            _ => Err(format!("unknown win rule {}", s))?,
+        }
+    }
+}
+
+/// The puzzle's rules, diagonals don't count
+const STANDARD_RULES: [WinRule; 2] = [WinRule::Rows, WinRule::Columns];
+
+/// Cell indices (row-major) of every line that wins under `rules` on a `size` by `size` board
+fn winning_lines(size: usize, rules: &[WinRule]) -> Vec<Vec<usize>> {
+    let mut lines = Vec::new();
+    for rule in rules {
+        match rule {
+            WinRule::Rows => {
+                lines.extend((0..size).map(|r| (0..size).map(|c| r * size + c).collect()))
             }
-        })
-        .sum()
+            WinRule::Columns => {
+                lines.extend((0..size).map(|c| (0..size).map(|r| r * size + c).collect()))
+            }
+            WinRule::Diagonals => {
+                lines.push((0..size).map(|i| i * size + i).collect());
+                lines.push((0..size).map(|i| i * size + (size - 1 - i)).collect());
+            }
+            WinRule::FourCorners => {
+                let last = size - 1;
+                let mut corners = vec![0, last, last * size, last * size + last];
+                corners.dedup();
+                lines.push(corners);
+            }
+            WinRule::FullCard => lines.push((0..size * size).collect()),
+        }
+    }
+    lines
 }
 
-fn parse_file(s: &str) -> (Vec<usize>, Vec<HashMap<usize, (usize, usize)>>) {
-    let mut lines = s.lines().filter(|l| !l.is_empty());
+/// The winning lines for one board size: which lines pass through each cell and how many cells
+/// each line has
+struct Layout {
+    cell_lines: Vec<Vec<usize>>,
+    lengths: Vec<usize>,
+}
 
-    let called = lines
-        .next()
-        .unwrap()
-        .split(',')
-        .map(|x| x.parse::<usize>().unwrap())
-        .collect::<Vec<_>>();
-
-    let remaining_lines = lines.collect::<Vec<_>>();
-
-    let maps = remaining_lines
-        .chunks(5)
-        .map(|chunk| {
-            let mut map = HashMap::new();
-            for r in 0..5 {
-                let line = chunk[r];
-                for (c, x) in line.split_whitespace().enumerate() {
-                    map.insert(x.parse::<usize>().unwrap(), (r, c));
-                }
+impl Layout {
+    fn new(size: usize, rules: &[WinRule]) -> Self {
+        let lines = winning_lines(size, rules);
+        let mut cell_lines = vec![Vec::new(); size * size];
+        for (l, line) in lines.iter().enumerate() {
+            for &cell in line {
+                cell_lines[cell].push(l);
             }
-            map
+        }
+        Self {
+            cell_lines,
+            lengths: lines.iter().map(|l| l.len()).collect(),
+        }
+    }
+}
+
+/// A square board of numbers and which of them have been called. Marked cells are tallied per
+/// winning line along with the sum of what's left, so marking is O(1) for row and column rules.
+#[derive(Debug, Clone)]
+struct BingoBoard {
+    size: usize,
+    /// Row-major
+    cells: Vec<usize>,
+    marked: Vec<bool>,
+    /// Marked cells on each line of the board's `Layout`
+    hits: Vec<usize>,
+    unmarked: usize,
+}
+
+impl BingoBoard {
+    fn new(size: usize, cells: Vec<usize>) -> Result<Self, Box<dyn Error>> {
+        if size == 0 || cells.len() != size * size {
+            Err(format!(
+                "{} numbers don't make a {}x{} board",
+                cells.len(),
+                size,
+                size
+            ))?
+        }
+        Ok(Self {
+            size,
+            marked: vec![false; cells.len()],
+            hits: Vec::new(),
+            unmarked: cells.iter().sum(),
+            cells,
         })
-        .collect::<Vec<_>>();
+    }
+
+    /// Clear every mark ready to play with `layout`
+    fn reset(&mut self, layout: &Layout) {
+        self.marked.iter_mut().for_each(|m| *m = false);
+        self.hits = vec![0; layout.lengths.len()];
+        self.unmarked = self.cells.iter().sum();
+    }
 
-    (called, maps)
+    /// Mark a cell, returning whether that completes a line
+    fn mark_cell(&mut self, cell: usize, layout: &Layout) -> bool {
+        if self.marked[cell] {
+            return false;
+        }
+        self.marked[cell] = true;
+        self.unmarked -= self.cells[cell];
+        let mut won = false;
+        for &l in layout.cell_lines[cell].iter() {
+            self.hits[l] += 1;
+            won |= self.hits[l] == layout.lengths[l];
+        }
+        won
+    }
+
+    /// Sum of the unmarked numbers
+    fn unmarked_sum(&self) -> usize {
+        self.unmarked
+    }
 }
 
-fn run_p1(called: &[usize], boards: &[HashMap<usize, (usize, usize)>]) -> Vec<(usize, usize)> {
-    let mut board_progress = vec![HashSet::new(); boards.len()];
-    for c in called {
-        let mut winning_board_idxs = Vec::new();
-        for (i, (b, bp)) in boards.iter().zip(board_progress.iter_mut()).enumerate() {
-            if let Some((r, c)) = b.get(c) {
-                bp.insert((*r, *c));
+impl FromStr for BingoBoard {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rows = s
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .collect::<Vec<_>>();
+        let mut cells = Vec::new();
+        for row in rows.iter() {
+            let before = cells.len();
+            for x in row.split_whitespace() {
+                cells.push(x.parse::<usize>()?);
             }
-            if check_for_bingo(bp) {
-                let score = score_board(b, bp);
-                let final_score = score * c;
-                winning_board_idxs.push((i, final_score))
+            if cells.len() - before != rows.len() {
+                Err(format!(
+                    "row {:?} should have {} numbers",
+                    row.trim(),
+                    rows.len()
+                ))?
             }
         }
-        if !winning_board_idxs.is_empty() {
-            return winning_board_idxs;
-        }
+        BingoBoard::new(rows.len(), cells)
     }
+}
+
+/// A board finishing: which board, the number whose call completed it and its final score
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Finish {
+    board: usize,
+    number: usize,
+    score: usize,
+}
 
-    Vec::new()
+/// A set of boards played under the same rules, with an index from each number to the cells
+/// holding it so a call only touches the boards it's on
+struct Game {
+    boards: Vec<BingoBoard>,
+    layouts: HashMap<usize, Layout>,
+    /// Board and cell of every occurrence of each number, in board order
+    index: HashMap<usize, Vec<(usize, usize)>>,
 }
 
-fn run_p2(called: &[usize], boards: &[HashMap<usize, (usize, usize)>]) -> (usize, usize) {
-    let mut board_progress = vec![HashSet::new(); boards.len()];
-    let mut last = None;
-    let mut bingo_mask = vec![false; boards.len()];
-    for c in called {
-        for (i, (b, bp)) in boards.iter().zip(board_progress.iter_mut()).enumerate() {
-            if let Some((r, c)) = b.get(c) {
-                bp.insert((*r, *c));
+impl Game {
+    fn new(mut boards: Vec<BingoBoard>, rules: &[WinRule]) -> Self {
+        let mut layouts = HashMap::new();
+        let mut index = HashMap::<usize, Vec<_>>::new();
+        for (b, board) in boards.iter_mut().enumerate() {
+            let layout = layouts
+                .entry(board.size)
+                .or_insert_with(|| Layout::new(board.size, rules));
+            board.reset(layout);
+            for (cell, &number) in board.cells.iter().enumerate() {
+                index.entry(number).or_default().push((b, cell));
             }
-            if check_for_bingo(bp) && !bingo_mask[i] {
-                bingo_mask[i] = true;
-                let score = score_board(b, bp);
-                let final_score = score * c;
-                last = Some((i, final_score));
+        }
+        Self {
+            boards,
+            layouts,
+            index,
+        }
+    }
+
+    /// Call every number and return the boards in the order they finish. Boards finishing on the
+    /// same call are in board order, and boards that never finish are left out.
+    fn play(mut self, called: &[usize]) -> Vec<Finish> {
+        let mut finished = vec![false; self.boards.len()];
+        let mut order = Vec::new();
+        for &number in called {
+            for &(b, cell) in self.index.get(&number).into_iter().flatten() {
+                let board = &mut self.boards[b];
+                if !finished[b] && board.mark_cell(cell, &self.layouts[&board.size]) {
+                    finished[b] = true;
+                    order.push(Finish {
+                        board: b,
+                        number,
+                        score: board.unmarked_sum() * number,
+                    });
+                }
             }
         }
+        order
     }
+}
 
-    last.unwrap()
+fn parse_file(s: &str) -> Result<(Vec<usize>, Vec<BingoBoard>), Box<dyn Error>> {
+    // Blocks are runs of non-blank lines, so separators may hold whitespace or a CR
+    let mut groups: Vec<Vec<&str>> = vec![];
+    let mut in_block = false;
+    for line in s.lines() {
+        if line.trim().is_empty() {
+            in_block = false;
+        } else if in_block {
+            groups.last_mut().unwrap().push(line);
+        } else {
+            groups.push(vec![line]);
+            in_block = true;
+        }
+    }
+    let mut blocks = groups.iter().map(|g| g.join("\n"));
+
+    let called = blocks
+        .next()
+        .ok_or("missing the called numbers")?
+        .trim()
+        .split(',')
+        .map(|x| x.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let boards = blocks.map(|b| b.parse()).collect::<Result<Vec<_>, _>>()?;
+
+    Ok((called, boards))
+}
+
+/// Index and score of every board finishing on the first winning call
+fn run_p1(called: &[usize], boards: &[BingoBoard]) -> Vec<(usize, usize)> {
+    let order = Game::new(boards.to_vec(), &STANDARD_RULES).play(called);
+    let first_number = order.first().map(|f| f.number);
+    order
+        .iter()
+        .take_while(|f| Some(f.number) == first_number)
+        .map(|f| (f.board, f.score))
+        .collect()
+}
+
+/// Index and score of the last board to finish
+fn run_p2(called: &[usize], boards: &[BingoBoard]) -> (usize, usize) {
+    let order = Game::new(boards.to_vec(), &STANDARD_RULES).play(called);
+    let last = order.last().unwrap();
+    (last.board, last.score)
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() != 2 && !(args.len() == 4 && args[2] == "--rules") {
+        panic!("usage: aoc_04 <input> [--rules rows,columns,diagonals,corners,full]");
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
-    let (called, boards) = parse_file(&file_str);
+    let (called, boards) = parse_file(&file_str).unwrap_or_else(|e| panic!("{}", e));
     let p1_idxs_and_scores = run_p1(&called, &boards);
     println!("p1: {}", p1_idxs_and_scores.first().unwrap().1);
     let p2_idx_and_score = run_p2(&called, &boards);
     println!("p2: {}", p2_idx_and_score.1);
+
+    if let Some(rules) = args.get(3) {
+        let rules = rules
+            .split(',')
+            .map(|r| r.parse())
+            .collect::<Result<Vec<WinRule>, _>>()
+            .unwrap_or_else(|e| panic!("{}", e));
+        for f in Game::new(boards, &rules).play(&called) {
+            println!(
+                "board {} finished on {} scoring {}",
+                f.board, f.number, f.score
+            );
+        }
+    }
 }
 
+#[cfg(test)]
+#[path = "common/rng.rs"]
+mod rng;
+
 #[cfg(test)]
 mod test_day4 {
+    use super::rng::XorShift64;
     use super::*;
+    use std::time::Instant;
 
-    #[test]
-    fn test() {
-        let example = r#"7,4,9,5,11,17,23,2,0,14,21,24,10,16,13,6,15,25,12,22,18,20,8,19,3,26,1
+    const EXAMPLE: &str = r#"7,4,9,5,11,17,23,2,0,14,21,24,10,16,13,6,15,25,12,22,18,20,8,19,3,26,1
 
 22 13 17 11  0
  8  2 23  4 24
@@ -146,12 +329,171 @@ mod test_day4 {
 22 11 13  6  5
  2  0 12  3  7"#;
 
-        let (called, boards) = parse_file(example);
+    /// Plays by re-checking every line of every board after each call
+    fn naive_order(called: &[usize], boards: &[BingoBoard], rules: &[WinRule]) -> Vec<Finish> {
+        let mut marked = boards
+            .iter()
+            .map(|b| vec![false; b.cells.len()])
+            .collect::<Vec<_>>();
+        let mut order = Vec::<Finish>::new();
+        for &number in called {
+            for (i, b) in boards.iter().enumerate() {
+                if order.iter().any(|f| f.board == i) {
+                    continue;
+                }
+                for (cell, &c) in b.cells.iter().enumerate() {
+                    marked[i][cell] |= c == number;
+                }
+                let lines = winning_lines(b.size, rules);
+                if lines.iter().any(|l| l.iter().all(|&c| marked[i][c])) {
+                    let unmarked = (0..b.cells.len())
+                        .filter(|&c| !marked[i][c])
+                        .map(|c| b.cells[c])
+                        .sum::<usize>();
+                    order.push(Finish {
+                        board: i,
+                        number,
+                        score: unmarked * number,
+                    });
+                }
+            }
+        }
+        order
+    }
+
+    /// Random boards of numbers below `range`, along with every number called in a random order
+    fn generate(
+        count: usize,
+        sizes: &[usize],
+        range: usize,
+        seed: u64,
+    ) -> (Vec<usize>, Vec<BingoBoard>) {
+        let mut rng = XorShift64::new(seed);
+        let mut next = |n: usize| rng.below(n as u64) as usize;
+        let shuffled = |next: &mut dyn FnMut(usize) -> usize| {
+            let mut numbers = (0..range).collect::<Vec<_>>();
+            for i in (1..range).rev() {
+                numbers.swap(i, next(i + 1));
+            }
+            numbers
+        };
+        let boards = (0..count)
+            .map(|_| {
+                let size = sizes[next(sizes.len())];
+                let cells = shuffled(&mut next)[..size * size].to_vec();
+                BingoBoard::new(size, cells).unwrap()
+            })
+            .collect();
+        (shuffled(&mut next), boards)
+    }
+
+    #[test]
+    fn test() {
+        let (called, boards) = parse_file(EXAMPLE).unwrap();
         let &(p1_i, p1_score) = run_p1(&called, &boards).first().unwrap();
         assert_eq!(p1_i, 2);
         assert_eq!(p1_score, 4512);
         let (p2_i, p2_score) = run_p2(&called, &boards);
         assert_eq!(p2_i, 1);
         assert_eq!(p2_score, 1924);
+
+        // Separator lines holding spaces, or CRLF line endings, still split the boards
+        let spaced = EXAMPLE.replace("\n\n", "\n  \t\n");
+        let crlf = EXAMPLE.replace('\n', "\r\n");
+        for input in [spaced, crlf] {
+            let (other_called, other_boards) = parse_file(&input).unwrap();
+            assert_eq!(other_called, called);
+            assert_eq!(other_boards.len(), 3);
+            assert_eq!(
+                run_p1(&other_called, &other_boards),
+                run_p1(&called, &boards)
+            );
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let (called, boards) = parse_file(EXAMPLE).unwrap();
+        let order = Game::new(boards.clone(), &STANDARD_RULES).play(&called);
+        assert_eq!(
+            order.iter().map(|f| f.board).collect::<Vec<_>>(),
+            vec![2, 0, 1]
+        );
+
+        // The whole card is marked after the last number each board needs
+        let full = Game::new(boards.clone(), &[WinRule::FullCard]).play(&called);
+        assert_eq!(full.len(), 3);
+        assert!(full.iter().all(|f| f.score == 0));
+
+        let finishes = |rules: &[WinRule]| {
+            Game::new(boards.clone(), rules)
+                .play(&called)
+                .iter()
+                .map(|f| (f.board, f.number, f.score))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            finishes(&[WinRule::FourCorners]),
+            vec![(2, 14, 3262), (1, 3, 0), (0, 1, 0)]
+        );
+        assert_eq!(
+            finishes(&[WinRule::Diagonals]),
+            vec![(2, 2, 494), (1, 22, 1496), (0, 19, 76)]
+        );
+        // Board 2's diagonal completes before its top row
+        let all = finishes(&[WinRule::Rows, WinRule::Columns, WinRule::Diagonals]);
+        assert_eq!(all[0], (2, 2, 494));
+
+        // Boards of other sizes
+        let (called, boards) = parse_file("1,5,9,4\n\n1 2\n3 4\n\n9 8 7\n6 5 4\n3 2 1\n").unwrap();
+        let order = Game::new(boards, &[WinRule::Diagonals]).play(&called);
+        assert_eq!(
+            order,
+            vec![
+                Finish {
+                    board: 1,
+                    number: 9,
+                    score: (8 + 7 + 6 + 4 + 3 + 2) * 9
+                },
+                Finish {
+                    board: 0,
+                    number: 4,
+                    score: (2 + 3) * 4
+                }
+            ]
+        );
+
+        assert!(parse_file("1\n\n1 2\n3\n").is_err());
+        assert!("rows,spirals"
+            .split(',')
+            .map(|r| r.parse::<WinRule>())
+            .any(|r| r.is_err()));
+    }
+
+    #[test]
+    fn test_matches_naive() {
+        let rule_sets: [&[WinRule]; 4] = [
+            &STANDARD_RULES,
+            &[WinRule::Diagonals, WinRule::FourCorners],
+            &[WinRule::FullCard],
+            &[WinRule::Rows, WinRule::Columns, WinRule::Diagonals],
+        ];
+        for (seed, rules) in rule_sets.iter().enumerate() {
+            let (called, boards) = generate(300, &[1, 3, 5, 6], 60, seed as u64 + 1);
+            let order = Game::new(boards.clone(), rules).play(&called);
+            assert_eq!(order, naive_order(&called, &boards, rules));
+            assert_eq!(order.len(), boards.len());
+        }
+    }
+
+    /// `cargo test --release --bin aoc_04 -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_large_game() {
+        let (called, boards) = generate(100_000, &[5], 100, 42);
+        let start = Instant::now();
+        let order = Game::new(boards, &STANDARD_RULES).play(&called);
+        assert_eq!(order.len(), 100_000);
+        eprintln!("100k boards: {:?}", start.elapsed());
     }
 }
@@ -1,73 +1,369 @@
 //! AoC 2021 - 05
 
-use std::{collections::HashMap, env};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
 
-fn parse_file(s: &str) -> Vec<((i64, i64), (i64, i64))> {
-    s.lines()
-        .map(|l| {
-            let mut chunks = l.split_whitespace();
-            let first_str = chunks.next().unwrap();
-            let _skip = chunks.next().unwrap();
-            let second_str = chunks.next().unwrap();
-
-            let mut first_pair_itr = first_str.split(',');
-            let first_x = first_pair_itr.next().unwrap().parse::<i64>().unwrap();
-            let first_y = first_pair_itr.next().unwrap().parse::<i64>().unwrap();
-
-            let mut second_pair_itr = second_str.split(',');
-            let second_x = second_pair_itr.next().unwrap().parse::<i64>().unwrap();
-            let second_y = second_pair_itr.next().unwrap().parse::<i64>().unwrap();
-            ((first_x, first_y), (second_x, second_y))
-        })
-        .collect::<Vec<_>>()
+type Point = (i64, i64);
+
+/// A line of vents, with its endpoints ordered so horizontal and diagonal lines run left to right
+/// and vertical lines run upwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Horizontal {
+        y: i64,
+        x1: i64,
+        x2: i64,
+    },
+    Vertical {
+        x: i64,
+        y1: i64,
+        y2: i64,
+    },
+    /// At 45 degrees, `y2 - y1` is `x2 - x1` or its negation
+    Diagonal {
+        x1: i64,
+        y1: i64,
+        x2: i64,
+        y2: i64,
+    },
+}
+
+impl Segment {
+    /// Segment between two points, which must be in line horizontally, vertically or at 45
+    /// degrees. A single point counts as horizontal.
+    fn new((x1, y1): Point, (x2, y2): Point) -> Result<Self, Box<dyn Error>> {
+        if y1 == y2 {
+            Ok(Segment::Horizontal {
+                y: y1,
+                x1: x1.min(x2),
+                x2: x1.max(x2),
+            })
+        } else if x1 == x2 {
+            Ok(Segment::Vertical {
+                x: x1,
+                y1: y1.min(y2),
+                y2: y1.max(y2),
+            })
+        } else if (x2 - x1).abs() == (y2 - y1).abs() {
+            let ((x1, y1), (x2, y2)) = if x1 < x2 {
+                ((x1, y1), (x2, y2))
+            } else {
+                ((x2, y2), (x1, y1))
+            };
+            Ok(Segment::Diagonal { x1, y1, x2, y2 })
+        } else {
+            Err(format!(
+                "{},{} -> {},{} isn't horizontal, vertical or diagonal",
+                x1, y1, x2, y2
+            ))?
+        }
+    }
+
+    fn is_diagonal(&self) -> bool {
+        matches!(self, Segment::Diagonal { .. })
+    }
+
+    /// First point, unit step and number of steps to the last point
+    fn ray(&self) -> (Point, Point, i64) {
+        match *self {
+            Segment::Horizontal { y, x1, x2 } => ((x1, y), (1, 0), x2 - x1),
+            Segment::Vertical { x, y1, y2 } => ((x, y1), (0, 1), y2 - y1),
+            Segment::Diagonal { x1, y1, x2, y2 } => ((x1, y1), (1, (y2 - y1).signum()), x2 - x1),
+        }
+    }
+
+    fn end(&self) -> Point {
+        let ((x, y), (dx, dy), len) = self.ray();
+        (x + len * dx, y + len * dy)
+    }
+
+    fn points(&self) -> impl Iterator<Item = Point> {
+        let ((x, y), (dx, dy), len) = self.ray();
+        (0..=len).map(move |t| (x + t * dx, y + t * dy))
+    }
+
+    /// The infinite line the segment lies on and the range of positions it covers along it
+    fn line(&self) -> (Line, (i64, i64)) {
+        let (start, dir, len) = self.ray();
+        let (key, pos) = line_position(dir, start);
+        ((dir, key), (pos, pos + len))
+    }
+}
+
+/// Parses exactly `x1,y1 -> x2,y2`
+impl FromStr for Segment {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let point = |p: &str| -> Result<Point, Self::Err> {
+            let (x, y) = p
+                .split_once(',')
+                .ok_or_else(|| format!("expected x,y but got {:?}", p))?;
+            Ok((x.parse()?, y.parse()?))
+        };
+        let (first, second) = s
+            .split_once(" -> ")
+            .ok_or_else(|| format!("expected x1,y1 -> x2,y2 but got {:?}", s))?;
+        Segment::new(point(first)?, point(second)?)
+    }
+}
+
+/// A line through the lattice as its unit step and a constant shared by every point on it
+type Line = (Point, i64);
+
+/// The constant of the line with step `dir` through `p`, and the position of `p` along it
+fn line_position(dir: Point, (x, y): Point) -> (i64, i64) {
+    match dir {
+        (1, 0) => (y, x),
+        (0, 1) => (x, y),
+        (1, 1) => (y - x, x),
+        _ => (y + x, x),
+    }
 }
 
-fn map_vents(
-    vents: &Vec<((i64, i64), (i64, i64))>,
-    count_diag: bool,
-) -> HashMap<(i64, i64), usize> {
-    let mut map = HashMap::new();
+/// Inverse of `line_position`
+fn line_point(((dx, dy), key): Line, pos: i64) -> Point {
+    match (dx, dy) {
+        (1, 0) => (pos, key),
+        (0, 1) => (key, pos),
+        _ => (pos, key + dy * pos),
+    }
+}
+
+/// The lattice point two non-parallel segments, given as rays, cross at if any
+fn crossing(
+    ((ax, ay), (adx, ady), a_len): (Point, Point, i64),
+    ((bx, by), (bdx, bdy), b_len): (Point, Point, i64),
+) -> Option<Point> {
+    // Solve a + s * da = b + t * db by Cramer's rule
+    let (wx, wy) = (bx - ax, by - ay);
+    let det = bdx * ady - adx * bdy;
+    let s_num = bdx * wy - bdy * wx;
+    let t_num = adx * wy - ady * wx;
+    if s_num % det != 0 || t_num % det != 0 {
+        // Perpendicular diagonals can cross between lattice points
+        return None;
+    }
+    let (s, t) = (s_num / det, t_num / det);
+    ((0..=a_len).contains(&s) && (0..=b_len).contains(&t)).then_some((ax + s * adx, ay + s * ady))
+}
+
+/// Overlap counts over the bounding box of the segments, row by row from `min`
+struct VentMap {
+    min: Point,
+    width: usize,
+    height: usize,
+    counts: Vec<u32>,
+}
+
+impl VentMap {
+    fn count_dangerous_vents(&self) -> usize {
+        self.counts.iter().filter(|&&c| c >= 2).count()
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &[u32]> {
+        self.counts.chunks(self.width.max(1)).take(self.height)
+    }
+
+    /// Plain (ASCII) greymap where brighter pixels have more overlapping vents
+    fn write_pgm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let max = self
+            .counts
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .clamp(1, 65535);
+        writeln!(w, "P2")?;
+        writeln!(w, "# vents from {},{}", self.min.0, self.min.1)?;
+        writeln!(w, "{} {}", self.width, self.height)?;
+        writeln!(w, "{}", max)?;
+        for row in self.rows() {
+            let row = row
+                .iter()
+                .map(|c| c.min(&max).to_string())
+                .collect::<Vec<_>>();
+            writeln!(w, "{}", row.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// One line of counts per row, with a header giving the x coordinate of each column and the
+    /// y coordinate leading each row
+    fn write_csv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let xs = (0..self.width).map(|i| (self.min.0 + i as i64).to_string());
+        writeln!(w, "y/x,{}", xs.collect::<Vec<_>>().join(","))?;
+        for (i, row) in self.rows().enumerate() {
+            let row = row.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+            writeln!(w, "{},{}", self.min.1 + i as i64, row.join(","))?;
+        }
+        Ok(())
+    }
+
+    /// Write the heatmap, picking the format from the extension
+    fn export(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("pgm") => self.write_pgm(&mut w),
+            Some("csv") => self.write_csv(&mut w),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "heatmap path should end in .pgm or .csv",
+            )),
+        }
+    }
+}
+
+/// Smallest and largest corners of the box around the segments
+fn bounds(vents: &[Segment]) -> Option<(Point, Point)> {
+    let mut points = vents.iter().flat_map(|v| [v.ray().0, v.end()]);
+    let first = points.next()?;
+    Some(points.fold((first, first), |(min, max), (x, y)| {
+        ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y)))
+    }))
+}
+
+/// Dense count of how many segments cover each point
+fn map_vents(vents: &[Segment]) -> VentMap {
+    let (min, max) = bounds(vents).unwrap_or(((0, 0), (-1, -1)));
+    let width = (max.0 - min.0 + 1) as usize;
+    let height = (max.1 - min.1 + 1) as usize;
+    let mut counts = vec![0; width * height];
+
+    for v in vents {
+        for (x, y) in v.points() {
+            counts[(y - min.1) as usize * width + (x - min.0) as usize] += 1;
+        }
+    }
 
-    for &((x1, y1), (x2, y2)) in vents {
-        if x1 == x2 {
-            let mut range = [y1, y2];
-            range.sort();
-            for y in range[0]..=range[1] {
-                *map.entry((x1, y)).or_insert(0) += 1;
+    VentMap {
+        min,
+        width,
+        height,
+        counts,
+    }
+}
+
+/// Counts points covered by at least two segments by intersecting every pair, so the cost
+/// doesn't depend on how long the segments are or how far apart they lie. Collinear pairs give
+/// ranges along their shared line, which are merged per line, and other pairs give at most one
+/// crossing point.
+fn count_overlaps_pairwise(vents: &[Segment]) -> usize {
+    let mut ranges: HashMap<Line, Vec<(i64, i64)>> = HashMap::new();
+    let mut crossings = HashSet::new();
+    for (i, a) in vents.iter().enumerate() {
+        let (a_line, (a_start, a_end)) = a.line();
+        for b in vents[i + 1..].iter() {
+            let (b_line, (b_start, b_end)) = b.line();
+            if a_line == b_line {
+                let (start, end) = (a_start.max(b_start), a_end.min(b_end));
+                if start <= end {
+                    ranges.entry(a_line).or_default().push((start, end));
+                }
+            } else if a_line.0 != b_line.0 {
+                crossings.extend(crossing(a.ray(), b.ray()));
             }
-        } else if y1 == y2 {
-            let mut range = [x1, x2];
-            range.sort();
-            for x in range[0]..=range[1] {
-                *map.entry((x, y1)).or_insert(0) += 1;
+        }
+    }
+
+    // Merge the ranges on each line so they're disjoint and sorted
+    for line_ranges in ranges.values_mut() {
+        line_ranges.sort_unstable();
+        let mut merged: Vec<(i64, i64)> = Vec::new();
+        for &(start, end) in line_ranges.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
             }
-        } else if count_diag {
-            let x_slope = (x2 - x1).signum();
-            let y_slope = (y2 - y1).signum();
-            let dist = (y2 - y1).abs();
-
-            for i in 0..=dist {
-                let x = x1 + x_slope * i;
-                let y = y1 + y_slope * i;
-                *map.entry((x, y)).or_insert(0) += 1;
+        }
+        *line_ranges = merged;
+    }
+    let merged = ranges
+        .iter()
+        .flat_map(|(&line, rs)| {
+            rs.iter()
+                .map(move |&(start, end)| (line_point(line, start), line.0, end - start))
+        })
+        .collect::<Vec<_>>();
+
+    // Each point is in at most one merged range per line, but ranges on different lines can
+    // cross, so those points would be counted once per range
+    let mut total: usize = merged.iter().map(|&(_, _, len)| len as usize + 1).sum();
+    let mut shared: HashMap<Point, usize> = HashMap::new();
+    for (i, &a) in merged.iter().enumerate() {
+        for &b in merged[i + 1..].iter().filter(|b| b.1 != a.1) {
+            if let Some(p) = crossing(a, b) {
+                *shared.entry(p).or_default() += 1;
             }
         }
     }
+    for pairs in shared.into_values() {
+        // k ranges through a point give k * (k - 1) / 2 pairs
+        let k = (1..=4).find(|k| k * (k - 1) / 2 == pairs).unwrap();
+        total -= k - 1;
+    }
 
-    map
+    let covered = |p: Point| {
+        [(1, 0), (0, 1), (1, 1), (1, -1)].iter().any(|&dir| {
+            let (key, pos) = line_position(dir, p);
+            ranges.get(&(dir, key)).is_some_and(|rs| {
+                let i = rs.partition_point(|&(_, end)| end < pos);
+                i < rs.len() && rs[i].0 <= pos
+            })
+        })
+    };
+    total + crossings.into_iter().filter(|&p| !covered(p)).count()
+}
+
+/// Above this many cells in the bounding box the pairwise count is used instead of a dense map
+const DENSE_LIMIT: i128 = 1 << 24;
+
+/// Cells in the bounding box of all vents, in i128 so that wide inputs can't overflow
+fn bounding_area(vents: &[Segment]) -> i128 {
+    match bounds(vents) {
+        Some((min, max)) => {
+            (max.0 as i128 - min.0 as i128 + 1) * (max.1 as i128 - min.1 as i128 + 1)
+        }
+        None => 0,
+    }
+}
+
+fn count_overlaps(vents: &[Segment]) -> usize {
+    if vents.is_empty() {
+        0
+    } else if bounding_area(vents) <= DENSE_LIMIT {
+        map_vents(vents).count_dangerous_vents()
+    } else {
+        count_overlaps_pairwise(vents)
+    }
+}
+
+fn parse_file(s: &str) -> Result<Vec<Segment>, Box<dyn Error>> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.is_empty())
+        .map(|(i, l)| {
+            l.parse::<Segment>()
+                .map_err(|e| format!("line {}: {}", i + 1, e).into())
+        })
+        .collect()
 }
 
-fn count_dangerous_vents(mapped: &HashMap<(i64, i64), usize>) -> usize {
-    mapped.iter().filter(|((_x, _y), v)| **v >= 2).count()
+fn straight(vents: &[Segment]) -> Vec<Segment> {
+    vents.iter().filter(|v| !v.is_diagonal()).copied().collect()
 }
 
-fn run(s: &str) -> (usize, usize) {
-    let vents = parse_file(&s);
-    let p1_mapped = map_vents(&vents, false);
-    let p1_count = count_dangerous_vents(&p1_mapped);
+fn run(vents: &[Segment]) -> (usize, usize) {
+    let straight = straight(vents);
+    let p1_count = count_overlaps(&straight);
     println!("p1: {}", p1_count);
-    let p2_mapped = map_vents(&vents, true);
-    let p2_count = count_dangerous_vents(&p2_mapped);
+    let p2_count = count_overlaps(vents);
     println!("p2: {}", p2_count);
     (p1_count, p2_count)
 }
@@ -75,15 +371,36 @@ fn run(s: &str) -> (usize, usize) {
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() < 2 {
+        panic!("usage: aoc_05 <input> [--straight] [heatmap.pgm|csv]");
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
-    run(&file_str);
+    let vents = parse_file(&file_str).unwrap_or_else(|e| panic!("{}", e));
+    run(&vents);
+
+    let mut heatmap_vents = vents.clone();
+    for arg in args[2..].iter() {
+        match arg.as_str() {
+            "--straight" => heatmap_vents = straight(&vents),
+            path => {
+                if bounding_area(&heatmap_vents) > DENSE_LIMIT {
+                    panic!("the vents cover too large an area for a heatmap");
+                }
+                map_vents(&heatmap_vents)
+                    .export(Path::new(path))
+                    .expect("couldn't write the heatmap");
+            }
+        }
+    }
 }
 
+#[cfg(test)]
+#[path = "common/rng.rs"]
+mod rng;
+
 #[cfg(test)]
 mod test_day5 {
+    use super::rng::XorShift64;
     use super::*;
 
     #[test]
@@ -99,8 +416,132 @@ mod test_day5 {
 0,0 -> 8,8
 5,5 -> 8,2"#;
 
-        let (p1_count, p2_count) = run(&example);
+        let vents = parse_file(example).unwrap();
+        let (p1_count, p2_count) = run(&vents);
         assert_eq!(p1_count, 5);
         assert_eq!(p2_count, 12);
+        assert_eq!(count_overlaps_pairwise(&vents), 12);
+    }
+
+    #[test]
+    fn test_segments() {
+        assert!(Segment::new((0, 0), (2, 1)).is_err());
+        assert_eq!(
+            Segment::new((3, 5), (1, 7)).unwrap(),
+            Segment::Diagonal {
+                x1: 1,
+                y1: 7,
+                x2: 3,
+                y2: 5
+            }
+        );
+        assert_eq!(
+            Segment::new((3, 5), (1, 7))
+                .unwrap()
+                .points()
+                .collect::<Vec<_>>(),
+            vec![(1, 7), (2, 6), (3, 5)]
+        );
+
+        // Perpendicular diagonals crossing between lattice points
+        let a = Segment::new((0, 0), (1, 1)).unwrap();
+        let b = Segment::new((0, 1), (1, 0)).unwrap();
+        assert_eq!(crossing(a.ray(), b.ray()), None);
+
+        // Vertical lines touching at the end of a horizontal overlap
+        let touching = [
+            Segment::new((5, 0), (5, 3)).unwrap(),
+            Segment::new((5, 3), (5, 6)).unwrap(),
+            Segment::new((0, 3), (10, 3)).unwrap(),
+            Segment::new((4, 3), (8, 3)).unwrap(),
+        ];
+        assert_eq!(count_overlaps_pairwise(&touching), 5);
+        assert_eq!(map_vents(&touching).count_dangerous_vents(), 5);
+        assert_eq!(count_overlaps_pairwise(&[a, b]), 0);
+
+        // Far apart and very long lines
+        let huge = [
+            Segment::new((-1_000_000_000, 0), (1_000_000_000, 0)).unwrap(),
+            Segment::new((0, -1_000_000_000), (0, 1_000_000_000)).unwrap(),
+            Segment::new((-5, -5), (1_000_000_000, 1_000_000_000)).unwrap(),
+            Segment::new((500, 0), (2_000_000_000, 0)).unwrap(),
+        ];
+        // The crossing at the origin and 500..=1e9 along the x axis
+        assert_eq!(count_overlaps(&huge), 1 + 999_999_501);
+
+        // The bounding box has more cells than fit in an i64
+        let wide = [
+            Segment::new(
+                (-3_000_000_000, -3_000_000_000),
+                (3_000_000_000, 3_000_000_000),
+            )
+            .unwrap(),
+            Segment::new((-3_000_000_000, 0), (3_000_000_000, 0)).unwrap(),
+        ];
+        assert!(bounding_area(&wide) > i64::MAX as i128);
+        assert_eq!(count_overlaps(&wide), 1);
+    }
+
+    #[test]
+    fn test_pairwise_matches_dense() {
+        let mut rng = XorShift64::new(0x853c49e6748fea9b);
+        let mut next = |n: u64| rng.below(n) as i64;
+
+        for _ in 0..200 {
+            let vents = (0..1 + next(40))
+                .map(|_| {
+                    let (x, y) = (next(20), next(20));
+                    let len = next(15);
+                    let end = match next(4) {
+                        0 => (x + len, y),
+                        1 => (x, y - len),
+                        2 => (x + len, y + len),
+                        _ => (x - len, y + len),
+                    };
+                    Segment::new((x, y), end).unwrap()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                count_overlaps_pairwise(&vents),
+                map_vents(&vents).count_dangerous_vents(),
+                "{:?}",
+                vents
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_and_heatmap() {
+        assert_eq!(
+            "3,4 -> 1,4".parse::<Segment>().unwrap(),
+            Segment::Horizontal { y: 4, x1: 1, x2: 3 }
+        );
+        for bad in [
+            "3,4 => 1,4",
+            "3,4 foo 1,4",
+            "3,4 ->  1,4",
+            "3 4 -> 1,4",
+            "3,4 -> 1,4,5",
+            "3,4 -> 1,7",
+        ] {
+            assert!(bad.parse::<Segment>().is_err(), "{}", bad);
+        }
+        let err = parse_file("0,0 -> 2,2\n0,0 -> 1,2\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"), "{}", err);
+
+        let vents = parse_file("1,1 -> 3,1\n2,0 -> 2,2\n").unwrap();
+        let map = map_vents(&vents);
+        let mut pgm = Vec::new();
+        map.write_pgm(&mut pgm).unwrap();
+        assert_eq!(
+            String::from_utf8(pgm).unwrap(),
+            "P2\n# vents from 1,0\n3 3\n2\n0 1 0\n1 2 1\n0 1 0\n"
+        );
+        let mut csv = Vec::new();
+        map.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "y/x,1,2,3\n0,0,1,0\n1,1,2,1\n2,0,1,0\n"
+        );
     }
 }
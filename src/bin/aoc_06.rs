@@ -1,91 +1,269 @@
 //! AoC 2021 - 06
 
-use std::env;
+use std::{env, error::Error};
 
-fn parse_file(s: &str) -> Vec<i64> {
+fn parse_file(s: &str) -> Vec<usize> {
     s.lines()
         .filter(|l| !l.is_empty())
-        .map(|l| l.split(',').map(|s| s.parse::<i64>().unwrap()))
-        .flatten()
+        .flat_map(|l| l.split(',').map(|s| s.trim().parse::<usize>().unwrap()))
         .collect::<Vec<_>>()
 }
 
-fn sim_fish(val: i64) -> (i64, bool) {
-    let mut new_val = val;
-    new_val -= 1;
-    if new_val < 0 {
-        (6, true)
-    } else {
-        (new_val, false)
+/// How fish reproduce: a fish whose timer is 0 resets it to `cycle - 1` and spawns a fish whose
+/// timer starts at `cycle - 1 + newborn_delay`. The puzzle's fish have a cycle of 7 and a delay
+/// of 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LifecycleModel {
+    cycle: usize,
+    newborn_delay: usize,
+}
+
+impl Default for LifecycleModel {
+    fn default() -> Self {
+        Self {
+            cycle: 7,
+            newborn_delay: 2,
+        }
     }
 }
 
-fn sim_fish_list_p1(fishes: &mut Vec<i64>, rounds: usize) {
-    for _i in 0..rounds {
-        let mut new_fishes = 0;
-        for f in fishes.iter_mut() {
-            let (new_val, new_fish) = sim_fish(*f);
-            *f = new_val;
-            if new_fish {
-                new_fishes += 1;
+type Matrix = Vec<Vec<u128>>;
+
+impl LifecycleModel {
+    /// Number of distinct timer values
+    fn states(&self) -> usize {
+        self.cycle + self.newborn_delay
+    }
+
+    /// Count of fish with each timer value
+    fn buckets(&self, fishes: &[usize]) -> Result<Vec<u128>, Box<dyn Error>> {
+        if self.cycle == 0 {
+            Err("the cycle must be at least one day")?
+        }
+        let mut counts = vec![0; self.states()];
+        for &f in fishes {
+            if f >= self.states() {
+                Err(format!("timer {} is longer than a newborn's", f))?
             }
+            counts[f] += 1;
         }
-        fishes.extend(std::iter::repeat(8).take(new_fishes));
+        Ok(counts)
     }
-}
 
-fn sim_fish_list_p2(fishes: &Vec<i64>, rounds: usize) -> usize {
-    let mut fish_time_counts: Vec<usize> = vec![0; 10]; // last place is gutter for rotation
-    for f in fishes {
-        fish_time_counts[*f as usize] += 1;
+    /// One day as a matrix on the buckets. Every timer counts down, and fish at 0 go to both the
+    /// reset and newborn timers, so this is the companion matrix of the population's recurrence
+    /// `f(n) = f(n - cycle) + f(n - cycle - newborn_delay)`.
+    fn transition(&self) -> Matrix {
+        let n = self.states();
+        let mut t = vec![vec![0; n]; n];
+        for (timer, row) in t.iter_mut().enumerate().take(n - 1) {
+            row[timer + 1] = 1;
+        }
+        t[self.cycle - 1][0] += 1;
+        t[n - 1][0] += 1;
+        t
+    }
+
+    /// Buckets after `days` days by repeated squaring of the transition matrix, with the given
+    /// addition and multiplication, which return `None` on overflow
+    fn power_apply(
+        &self,
+        mut counts: Vec<u128>,
+        days: u64,
+        add: impl Fn(u128, u128) -> Option<u128>,
+        mul: impl Fn(u128, u128) -> Option<u128>,
+    ) -> Option<Vec<u128>> {
+        let dot = |row: &[u128], col: &mut dyn Iterator<Item = u128>| {
+            row.iter()
+                .zip(col)
+                .try_fold(0, |acc, (&x, y)| add(acc, mul(x, y)?))
+        };
+
+        let mut power = self.transition();
+        let mut remaining = days;
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                counts = power
+                    .iter()
+                    .map(|row| dot(row, &mut counts.iter().copied()))
+                    .collect::<Option<_>>()?;
+            }
+            remaining >>= 1;
+            if remaining > 0 {
+                power = power
+                    .iter()
+                    .map(|row| {
+                        (0..row.len())
+                            .map(|c| dot(row, &mut power.iter().map(|r| r[c])))
+                            .collect()
+                    })
+                    .collect::<Option<_>>()?;
+            }
+        }
+        Some(counts)
     }
-    for _i in 0..rounds {
-        fish_time_counts.rotate_left(1);
-        let rolled_counts = fish_time_counts[9];
-        fish_time_counts[9] = 0;
-        fish_time_counts[6] += rolled_counts;
-        fish_time_counts[8] += rolled_counts;
+
+    /// Exact population after `days` days, `None` if it doesn't fit in a u128
+    fn population(&self, fishes: &[usize], days: u64) -> Result<Option<u128>, Box<dyn Error>> {
+        let counts = self.buckets(fishes)?;
+        let add = |a: u128, b: u128| a.checked_add(b);
+        let mul = |a: u128, b: u128| a.checked_mul(b);
+        Ok(self
+            .power_apply(counts, days, add, mul)
+            .and_then(|c| c.into_iter().try_fold(0, add)))
     }
 
-    fish_time_counts.iter().sum()
+    /// Population after `days` days modulo `modulus`, for day counts where it would be too big
+    fn population_mod(
+        &self,
+        fishes: &[usize],
+        days: u64,
+        modulus: u64,
+    ) -> Result<u64, Box<dyn Error>> {
+        if modulus == 0 {
+            Err("the modulus must be positive")?
+        }
+        let m = modulus as u128;
+        let counts = self.buckets(fishes)?.into_iter().map(|c| c % m).collect();
+        // Everything is reduced below 2^64 so neither can overflow
+        let add = |a: u128, b: u128| Some((a + b) % m);
+        let mul = |a: u128, b: u128| Some(a * b % m);
+        let counts = self.power_apply(counts, days, add, mul).unwrap();
+        Ok(counts.into_iter().fold(0, |acc, c| (acc + c) % m) as u64)
+    }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() < 2 {
+        panic!("usage: aoc_06 <input> [--cycle N] [--delay N] [--days N] [--mod P]");
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
-    {
-        let mut fishes = parse_file(&file_str);
-        sim_fish_list_p1(&mut fishes, 80);
-        let p1_count = fishes.len();
-        println!("p1: {}", p1_count);
+    let fishes = parse_file(&file_str);
+
+    let mut model = LifecycleModel::default();
+    let mut days = None;
+    let mut modulus = None;
+    let mut args_iter = args[2..].iter();
+    while let Some(arg) = args_iter.next() {
+        let value = args_iter.next().expect("options need a value");
+        match arg.as_str() {
+            "--cycle" => model.cycle = value.parse().expect("cycle should be a number"),
+            "--delay" => model.newborn_delay = value.parse().expect("delay should be a number"),
+            "--days" => days = Some(value.parse::<u64>().expect("days should be a number")),
+            "--mod" => modulus = Some(value.parse::<u64>().expect("modulus should be a number")),
+            _ => panic!("unknown option {}", arg),
+        }
     }
-    {
-        let mut fishes = parse_file(&file_str);
-        let p2_count = sim_fish_list_p2(&mut fishes, 256);
-        println!("p2: {}", p2_count);
+
+    let p1_count = model.population(&fishes, 80).unwrap().unwrap();
+    println!("p1: {}", p1_count);
+    let p2_count = model.population(&fishes, 256).unwrap().unwrap();
+    println!("p2: {}", p2_count);
+    if let Some(days) = days {
+        match modulus {
+            Some(m) => {
+                let count = model.population_mod(&fishes, days, m).unwrap();
+                println!("{} days: {} (mod {})", days, count, m);
+            }
+            None => match model.population(&fishes, days).unwrap() {
+                Some(count) => println!("{} days: {}", days, count),
+                None => println!("{} days: overflows a u128, try --mod", days),
+            },
+        }
     }
 }
 
+#[cfg(test)]
+#[path = "common/rng.rs"]
+mod rng;
+
 #[cfg(test)]
 mod test_day6 {
+    use super::rng::XorShift64;
     use super::*;
 
+    /// Simulates every fish individually
+    fn sim_fish_list(model: &LifecycleModel, fishes: &[usize], days: usize) -> usize {
+        let mut fishes = fishes.to_vec();
+        for _i in 0..days {
+            let mut new_fishes = 0;
+            for f in fishes.iter_mut() {
+                if *f == 0 {
+                    *f = model.cycle - 1;
+                    new_fishes += 1;
+                } else {
+                    *f -= 1;
+                }
+            }
+            fishes.extend(std::iter::repeat_n(model.states() - 1, new_fishes));
+        }
+        fishes.len()
+    }
+
     #[test]
     fn test() {
         let example = "3,4,3,1,2";
-        {
-            let mut fishes = parse_file(&example);
-            let p1_count = sim_fish_list_p2(&mut fishes, 80);
-            assert_eq!(p1_count, 5934);
-        }
-        {
-            let mut fishes = parse_file(&example);
-            let p2_count = sim_fish_list_p2(&mut fishes, 256);
-            assert_eq!(p2_count, 26984457539);
+        let fishes = parse_file(example);
+        let model = LifecycleModel::default();
+        assert_eq!(model.population(&fishes, 18).unwrap(), Some(26));
+        assert_eq!(model.population(&fishes, 80).unwrap(), Some(5934));
+        assert_eq!(model.population(&fishes, 256).unwrap(), Some(26984457539));
+        assert_eq!(sim_fish_list(&model, &fishes, 80), 5934);
+    }
+
+    #[test]
+    fn test_models() {
+        let mut rng = XorShift64::new(0x2545f4914f6cdd1d);
+        let mut next = |n: u64| rng.below(n) as usize;
+
+        for _ in 0..200 {
+            let model = LifecycleModel {
+                cycle: 1 + next(6),
+                newborn_delay: next(4),
+            };
+            let fishes = (0..1 + next(5))
+                .map(|_| next(model.states() as u64))
+                .collect::<Vec<_>>();
+            let days = next(20);
+            let expected = sim_fish_list(&model, &fishes, days) as u128;
+            assert_eq!(
+                model.population(&fishes, days as u64).unwrap(),
+                Some(expected),
+                "{:?} {:?} {}",
+                model,
+                fishes,
+                days
+            );
+            let p = 1_000_000_007;
+            assert_eq!(
+                model.population_mod(&fishes, days as u64, p).unwrap() as u128,
+                expected % p as u128
+            );
         }
     }
+
+    #[test]
+    fn test_huge() {
+        let fishes = parse_file("3,4,3,1,2");
+        let model = LifecycleModel::default();
+        assert_eq!(
+            model.population(&fishes, 1_000_000_000_000_000).unwrap(),
+            None
+        );
+
+        // Huge day counts still follow the population recurrence
+        let p = 998_244_353;
+        let n = 1_000_000_000_000_000;
+        let f = |days| model.population_mod(&fishes, days, p).unwrap();
+        assert_eq!(f(n), (f(n - 7) + f(n - 9)) % p);
+        assert_eq!(
+            model.population_mod(&fishes, 256, p).unwrap(),
+            26984457539 % p
+        );
+
+        assert!(model.buckets(&[9]).is_err());
+        assert!(model.population_mod(&fishes, 1, 0).is_err());
+    }
 }
@@ -6,82 +6,200 @@ fn parse_file(s: &str) -> Vec<i64> {
     let mut v = s
         .lines()
         .filter(|l| !l.is_empty())
-        .map(|l| l.split(',').map(|s| s.parse::<i64>().unwrap()))
-        .flatten()
+        .flat_map(|l| l.split(',').map(|s| s.trim().parse::<i64>().unwrap()))
         .collect::<Vec<_>>();
-    v.sort();
+    v.sort_unstable();
     v
 }
 
+/// Fuel cost of moving a crab some distance. Costs must be convex and non-decreasing in the
+/// distance, so the total over all crabs is convex in the target position.
+trait CostFn {
+    fn cost(&self, distance: i64) -> i64;
+
+    /// Positions the optimum lies between (inclusive), given the sorted crabs. Anywhere from the
+    /// first to the last crab unless the cost function knows better.
+    fn search_range(&self, crabs: &[i64]) -> (i64, i64) {
+        (*crabs.first().unwrap(), *crabs.last().unwrap())
+    }
+}
+
+/// One fuel per step
+struct Linear;
+
+impl CostFn for Linear {
+    fn cost(&self, distance: i64) -> i64 {
+        distance
+    }
+
+    /// The (lower) median minimises the sum of absolute distances
+    fn search_range(&self, crabs: &[i64]) -> (i64, i64) {
+        let median = crabs[(crabs.len() - 1) / 2];
+        (median, median)
+    }
+}
+
+/// Each step costs one more than the last
+struct Triangular;
+
 fn cum_sum(x: i64) -> i64 {
     x * (x + 1) / 2
 }
 
-fn p2(crabs: &[i64]) -> (i64, i64) {
-    let &first_pos = crabs.first().unwrap();
-    let &last_pos = crabs.last().unwrap();
-
-    let mut winning_pos = 0;
-    let mut winning_score = i64::MAX;
-    for p in first_pos..=last_pos {
-        let score = crabs.iter().map(|c| cum_sum((c - p).abs())).sum::<i64>();
-        if score < winning_score {
-            winning_score = score;
-            winning_pos = p;
+impl CostFn for Triangular {
+    fn cost(&self, distance: i64) -> i64 {
+        cum_sum(distance)
+    }
+
+    /// The cost is (d^2 + d) / 2, the d^2 part is minimised at the mean and the d part shifts
+    /// that by at most half a position either way
+    fn search_range(&self, crabs: &[i64]) -> (i64, i64) {
+        let mean = crabs.iter().sum::<i64>() as f64 / crabs.len() as f64;
+        ((mean - 0.5).floor() as i64, (mean + 0.5).ceil() as i64)
+    }
+}
+
+/// Distance squared
+struct Quadratic;
+
+impl CostFn for Quadratic {
+    fn cost(&self, distance: i64) -> i64 {
+        distance * distance
+    }
+}
+
+/// Each step costs one more than the last until it reaches `cap` per step
+struct CappedStep {
+    cap: i64,
+}
+
+impl CostFn for CappedStep {
+    fn cost(&self, distance: i64) -> i64 {
+        if distance <= self.cap {
+            cum_sum(distance)
+        } else {
+            cum_sum(self.cap) + (distance - self.cap) * self.cap
         }
     }
-    assert!(winning_score != i64::MAX);
+}
 
-    (winning_pos, winning_score)
+fn total_cost<C: CostFn>(crabs: &[i64], pos: i64, cost_fn: &C) -> i64 {
+    crabs.iter().map(|c| cost_fn.cost((c - pos).abs())).sum()
 }
 
-fn p1(crabs: &[i64]) -> (i64, i64) {
-    let &first_pos = crabs.first().unwrap();
-    let &last_pos = crabs.last().unwrap();
-
-    let mut winning_pos = 0;
-    let mut winning_score = i64::MAX;
-    for p in first_pos..=last_pos {
-        let score = crabs.iter().map(|c| (c - p).abs()).sum::<i64>();
-        if score < winning_score {
-            winning_score = score;
-            winning_pos = p;
+/// Cheapest position to align the (sorted) crabs at and its total cost, picking the lowest
+/// position on ties. The total is convex, so binary search the cost function's range for the
+/// first position that's no worse than the one after it.
+fn optimize<C: CostFn>(crabs: &[i64], cost_fn: &C) -> (i64, i64) {
+    let (mut lo, mut hi) = cost_fn.search_range(crabs);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if total_cost(crabs, mid, cost_fn) <= total_cost(crabs, mid + 1, cost_fn) {
+            hi = mid;
+        } else {
+            lo = mid + 1;
         }
     }
-    assert!(winning_score != i64::MAX);
+    (lo, total_cost(crabs, lo, cost_fn))
+}
 
-    (winning_pos, winning_score)
+fn p1(crabs: &[i64]) -> (i64, i64) {
+    optimize(crabs, &Linear)
+}
+
+fn p2(crabs: &[i64]) -> (i64, i64) {
+    optimize(crabs, &Triangular)
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() != 2 && !(args.len() == 4 && args[2] == "--cost") {
+        panic!("usage: aoc_07 <input> [--cost quadratic|capped=N]");
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
-    let mut crabs = parse_file(&file_str);
-    let (pos, score) = p1(&mut crabs);
+    let crabs = parse_file(&file_str);
+    let (pos, score) = p1(&crabs);
     println!("P1: pos: {}, fuel: {}", pos, score);
-    let (p2_pos, p2_score) = p2(&mut crabs);
+    let (p2_pos, p2_score) = p2(&crabs);
     println!("P2: pos: {}, fuel: {}", p2_pos, p2_score);
+
+    if let Some(cost) = args.get(3) {
+        let (pos, score) = match cost.split_once('=') {
+            None if cost == "quadratic" => optimize(&crabs, &Quadratic),
+            Some(("capped", cap)) => {
+                let cap = cap.parse().expect("cap should be a number");
+                optimize(&crabs, &CappedStep { cap })
+            }
+            _ => panic!("unknown cost function {}", cost),
+        };
+        println!("{}: pos: {}, fuel: {}", cost, pos, score);
+    }
 }
 
+#[cfg(test)]
+#[path = "common/rng.rs"]
+mod rng;
+
 #[cfg(test)]
 mod test_day7 {
+    use super::rng::XorShift64;
     use super::*;
 
+    /// Tries every position between the first and last crab
+    fn brute_force<C: CostFn>(crabs: &[i64], cost_fn: &C) -> (i64, i64) {
+        let &first_pos = crabs.first().unwrap();
+        let &last_pos = crabs.last().unwrap();
+
+        let mut winning_pos = 0;
+        let mut winning_score = i64::MAX;
+        for p in first_pos..=last_pos {
+            let score = total_cost(crabs, p, cost_fn);
+            if score < winning_score {
+                winning_score = score;
+                winning_pos = p;
+            }
+        }
+        assert!(winning_score != i64::MAX);
+
+        (winning_pos, winning_score)
+    }
+
     #[test]
     fn test() {
         let example = "16,1,2,0,4,2,7,1,2,14";
         {
-            let mut crabs = parse_file(&example);
-            let (pos, score) = p1(&mut crabs);
+            let crabs = parse_file(example);
+            let (pos, score) = p1(&crabs);
             assert_eq!(pos, 2);
             assert_eq!(score, 37);
-            let (p2_pos, p2_score) = p2(&mut crabs);
+            let (p2_pos, p2_score) = p2(&crabs);
             assert_eq!(p2_pos, 5);
             assert_eq!(p2_score, 168);
         }
     }
+
+    #[test]
+    fn test_cost_fns() {
+        let mut rng = XorShift64::new(0x9e3779b97f4a7c15);
+        let mut next = |n: u64| rng.below(n) as i64;
+
+        for _ in 0..300 {
+            let mut crabs = (0..1 + next(30))
+                .map(|_| next(200) - 50)
+                .collect::<Vec<_>>();
+            crabs.sort_unstable();
+            assert_eq!(optimize(&crabs, &Linear), brute_force(&crabs, &Linear));
+            assert_eq!(
+                optimize(&crabs, &Triangular),
+                brute_force(&crabs, &Triangular)
+            );
+            assert_eq!(
+                optimize(&crabs, &Quadratic),
+                brute_force(&crabs, &Quadratic)
+            );
+            let capped = CappedStep { cap: 1 + next(20) };
+            assert_eq!(optimize(&crabs, &capped), brute_force(&crabs, &capped));
+        }
+    }
 }
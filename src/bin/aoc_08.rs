@@ -1,275 +1,368 @@
 //! AoC 2021 - 08
 
-use std::env;
-
-fn parse_file(s: &str) -> (Vec<Vec<&str>>, Vec<Vec<&str>>) {
-    let inputs_outputs = s
-        .lines()
-        .filter(|l| !l.is_empty())
-        .map(|l| {
-            let mut delim = l.split('|');
-            let inputs = delim.next().unwrap();
-            let outputs = delim.next().unwrap();
-
-            let input_strs = inputs
-                .split(' ')
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<_>>();
-            let output_strs = outputs
-                .split(' ')
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<_>>();
-            (input_strs, output_strs)
-        })
-        .collect::<Vec<_>>();
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    str::FromStr,
+};
 
-    let mut inputs = Vec::new();
-    let mut outputs = Vec::new();
-    for (vi, vo) in inputs_outputs.into_iter() {
-        inputs.push(vi);
-        outputs.push(vo);
-    }
+/// The puzzle's seven-segment digits in the glyph set config format
+const SEVEN_SEGMENT: &str = "segments: abcdefg
+0: abcefg
+1: cf
+2: acdeg
+3: acdfg
+4: bcdf
+5: abdfg
+6: abdefg
+7: acf
+8: abcdefg
+9: abcdfg
+";
 
-    (inputs, outputs)
+/// A display's segment alphabet and the symbols it can show, segments are stored as bitmasks
+/// with bit N for the Nth letter of the alphabet.
+///
+/// Config files have a `segments: <letters>` line followed by one `<symbol>: <letters>` line per
+/// glyph, blank lines and lines starting with `#` are ignored.
+#[derive(Debug)]
+struct GlyphSet {
+    segments: Vec<char>,
+    glyphs: Vec<(char, u32)>,
 }
 
-fn p1(outputs: &[Vec<&str>]) -> usize {
-    outputs
-        .into_iter()
-        .map(|s| s)
-        .flatten()
-        .filter(|s| match s.len() {
-            2..=4 => true, // 1 7,4
-            7 => true,     // 8
-            _ => false,
-        })
-        .count()
-}
+impl GlyphSet {
+    fn new(segments: Vec<char>, glyphs: Vec<(char, u32)>) -> Result<Self, Box<dyn Error>> {
+        if segments.len() > 32 {
+            Err(format!(
+                "{} segments is more than the 32 supported",
+                segments.len()
+            ))?
+        }
+        if let Some(c) = segments
+            .iter()
+            .find(|c| segments.iter().filter(|d| d == c).count() > 1)
+        {
+            Err(format!("segment {} is listed twice", c))?
+        }
+        let mut seen = HashMap::new();
+        for &(symbol, pattern) in glyphs.iter() {
+            if pattern == 0 {
+                Err(format!("glyph {} lights no segments", symbol))?
+            }
+            if let Some(other) = seen.insert(pattern, symbol) {
+                Err(format!(
+                    "glyphs {} and {} have the same pattern",
+                    other, symbol
+                ))?
+            }
+        }
 
-// cur_char should not be locked (i.e. it should have more than one possibility)
-fn p2_rec(
-    possibs: HashMap<char, HashSet<char>>,
-    cur_char: char,
-    strs: &[&str],
-) -> Option<HashMap<char, char>> {
-    eprintln!("========");
-    eprintln!("{}:", cur_char);
-    eprintln!("\t{}: {:?}:", 'a', possibs[&'a']);
-    eprintln!("\t{}: {:?}:", 'b', possibs[&'b']);
-    eprintln!("\t{}: {:?}:", 'c', possibs[&'c']);
-    eprintln!("\t{}: {:?}:", 'd', possibs[&'d']);
-    eprintln!("\t{}: {:?}:", 'e', possibs[&'e']);
-    eprintln!("\t{}: {:?}:", 'f', possibs[&'f']);
-    eprintln!("\t{}: {:?}:", 'g', possibs[&'g']);
-    eprintln!("========");
-
-    let cur_char_pos = &possibs[&cur_char];
-    assert!(cur_char_pos.len() > 1);
-
-    for p in cur_char_pos.iter() {
-        // in each iteration, assign the current character one of it's possibilities
-        let mut new_pos = possibs.clone();
-
-        // remove that assigned values from others
-        for (_np_k, np_v) in new_pos.iter_mut() {
-            np_v.remove(p);
+        let set = Self { segments, glyphs };
+        let patterns = set.patterns();
+        let colours = &refine(set.segments.len(), &[&patterns])[0];
+        for (i, c) in colours.iter().enumerate() {
+            if let Some(j) = colours[i + 1..].iter().position(|d| d == c) {
+                Err(format!(
+                    "ambiguous glyph set, segments {} and {} can't be told apart",
+                    set.segments[i],
+                    set.segments[i + 1 + j]
+                ))?
+            }
         }
 
-        // the current characters possibilities are locked to this one assigned value
-        *new_pos.get_mut(&cur_char).unwrap() = [*p].iter().copied().collect();
+        Ok(set)
+    }
 
-        // if this assignment means something else has no possibilities then
-        // the assigment is unsatisfiable so skip it
-        if new_pos.iter().any(|(_k, v)| v.is_empty()) {
-            continue;
-        }
+    fn len(&self) -> usize {
+        self.glyphs.len()
+    }
 
-        // select the next character to try, we choose the one with the fewest
-        // possibilities (but more than one)
-        let mut counts = new_pos
+    fn patterns(&self) -> Vec<u32> {
+        self.glyphs.iter().map(|&(_, p)| p).collect()
+    }
+
+    /// Convert a string of segment letters into a bitmask
+    fn mask(&self, s: &str) -> Result<u32, Box<dyn Error>> {
+        s.chars().try_fold(0, |acc, c| {
+            let bit = self
+                .segments
+                .iter()
+                .position(|&seg| seg == c)
+                .ok_or_else(|| format!("unknown segment {}", c))?;
+            Ok(acc | 1 << bit)
+        })
+    }
+
+    /// Whether a pattern with this many lit segments can only be one glyph
+    fn is_unique_length(&self, len: u32) -> bool {
+        self.glyphs
             .iter()
-            .map(|(&k, v)| (k, v.len()))
-            .collect::<Vec<_>>();
-        counts.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-        if let Some((chr, _count)) = counts.iter().find(|(_chr, count)| *count > 1) {
-            if let Some(res) = p2_rec(new_pos, *chr, strs) {
-                return Some(res);
-            }
-        } else if counts.iter().all(|(_k, v)| *v == 1) {
-            // If there's nothing left to try, then we have a mapping that doesn't violate any
-            // constraints so far, but it may produce digits that don't exist so check and skip
-            // as needed.
+            .filter(|(_, p)| p.count_ones() == len)
+            .count()
+            == 1
+    }
+
+    fn symbol(&self, pattern: u32) -> Option<char> {
+        self.glyphs
+            .iter()
+            .find(|&&(_, p)| p == pattern)
+            .map(|&(s, _)| s)
+    }
+
+    /// Deduce the wiring from one scrambled pattern per glyph
+    fn deduce(&self, patterns: &[u32]) -> Result<Wiring, Box<dyn Error>> {
+        if patterns.len() != self.len() {
+            Err(format!(
+                "expected {} signal patterns, got {}",
+                self.len(),
+                patterns.len()
+            ))?
+        }
+        if patterns.iter().collect::<HashSet<_>>().len() != patterns.len() {
+            Err("signal patterns aren't distinct")?
+        }
 
-            let maybe_result = new_pos
+        let canonical = self.patterns();
+        let colours = refine(self.segments.len(), &[&canonical, patterns]);
+        let mut wires = vec![0; self.segments.len()];
+        for (wire, target) in wires.iter_mut().enumerate() {
+            let seg = colours[0]
                 .iter()
-                .map(|(k, v)| (*k, *v.iter().next().unwrap()))
-                .collect::<HashMap<_, _>>();
-            let invalid = strs.iter().any(|oo| {
-                let tgt = oo
-                    .chars()
-                    .map(|c| *maybe_result.iter().find(|(_k, v)| **v == c).unwrap().0)
-                    .collect::<Vec<_>>();
-                identify_digit(&tgt).is_none()
-            });
-            if !invalid {
-                return Some(
-                    new_pos
-                        .iter()
-                        .map(|(k, v)| (*k, *v.iter().next().unwrap()))
-                        .collect(),
-                );
-            } else {
-                eprintln!("discarding mapping because it's invalid");
-            }
+                .position(|&c| c == colours[1][wire])
+                .ok_or_else(|| format!("wire {} doesn't match any segment", self.segments[wire]))?;
+            *target = 1 << seg;
         }
+
+        let wiring = Wiring(wires);
+        if patterns
+            .iter()
+            .any(|&p| self.symbol(wiring.translate(p)).is_none())
+        {
+            Err("signal patterns don't match the glyph set")?
+        }
+
+        Ok(wiring)
     }
 
-    None
+    /// Decode the output patterns of a display into its symbols
+    fn decode(&self, wiring: &Wiring, outputs: &[u32]) -> Result<String, Box<dyn Error>> {
+        outputs
+            .iter()
+            .map(|&o| {
+                self.symbol(wiring.translate(o))
+                    .ok_or_else(|| "output isn't a valid glyph".into())
+            })
+            .collect()
+    }
 }
 
-fn intersect(cur: &mut HashSet<char>, new: &HashSet<char>) {
-    let new_set = cur.intersection(new).copied().collect::<HashSet<_>>();
-    *cur = new_set;
+impl Default for GlyphSet {
+    fn default() -> Self {
+        SEVEN_SEGMENT.parse().unwrap()
+    }
 }
 
-fn identify_digit(chars: &[char]) -> Option<usize> {
-    let mut sorted = chars.iter().collect::<Vec<_>>();
-    sorted.sort();
-    let s: String = sorted.iter().copied().collect();
-    eprintln!("mapping: {}", s);
-    match s.as_str() {
-        "abcefg" => Some(0),
-        "cf" => Some(1),
-        "acdeg" => Some(2),
-        "acdfg" => Some(3),
-        "bcdf" => Some(4),
-        "abdfg" => Some(5),
-        "abdefg" => Some(6),
-        "acf" => Some(7),
-        "abcdefg" => Some(8),
-        "abcdfg" => Some(9),
-        _ => {
-            None
-            //unreachable!("unknown char digit mapping");
+impl FromStr for GlyphSet {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'));
+
+        let segments = lines
+            .next()
+            .and_then(|l| l.strip_prefix("segments:"))
+            .ok_or("glyph set should start with a segments line")?
+            .trim()
+            .chars()
+            .collect::<Vec<_>>();
+
+        let mut glyphs = Vec::new();
+        for l in lines {
+            let (symbol, letters) = l
+                .split_once(':')
+                .ok_or("glyph lines are <symbol>: <segments>")?;
+            let mut symbol_chars = symbol.trim().chars();
+            let symbol = match (symbol_chars.next(), symbol_chars.next()) {
+                (Some(c), None) => c,
+                _ => Err(format!("glyph symbol {:?} should be one character", symbol))?,
+            };
+            let mut pattern = 0u32;
+            for c in letters.trim().chars() {
+                let bit = segments
+                    .iter()
+                    .position(|&seg| seg == c)
+                    .ok_or_else(|| format!("glyph {} uses unknown segment {}", symbol, c))?;
+                pattern |= 1 << bit;
+            }
+            glyphs.push((symbol, pattern));
         }
+
+        Self::new(segments, glyphs)
     }
 }
 
-use std::collections::HashMap;
-use std::collections::HashSet;
-fn p2(inputs: &[Vec<&str>], outputs: &[Vec<&str>]) -> usize {
-    let all_set: HashSet<_> = ['a', 'b', 'c', 'd', 'e', 'f', 'g']
+/// Colour refinement over the segment/glyph incidence of one or more displays sharing an
+/// alphabet size. Glyphs start coloured by their length, then segments are coloured by the
+/// colours of the glyphs lighting them (for the seven-segment digits the first round is the
+/// usage frequency split by membership of 1, 4, 7 and 8) and glyphs by the colours of their
+/// segments until the partition stops changing. Colours are shared between the displays so
+/// equal colours mean the same role, returns the segment colours of each display.
+fn refine(segments: usize, displays: &[&[u32]]) -> Vec<Vec<usize>> {
+    let mut glyph_colours = displays
         .iter()
-        .map(|x| *x)
-        .collect();
-    let init_map = vec![
-        ('a', all_set.clone()),
-        ('b', all_set.clone()),
-        ('c', all_set.clone()),
-        ('d', all_set.clone()),
-        ('e', all_set.clone()),
-        ('f', all_set.clone()),
-        ('g', all_set.clone()),
-    ]
-    .into_iter()
-    .collect::<HashMap<_, _>>();
-
-    let mut output_numerals = Vec::new();
-    for (i, o) in inputs.iter().zip(outputs.iter()) {
-        let io = {
-            let mut tmp = i.clone();
-            tmp.extend(o.iter());
-            tmp
-        };
+        .map(|ps| {
+            ps.iter()
+                .map(|p| p.count_ones() as usize)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut seg_colours = vec![vec![0; segments]; displays.len()];
+    let mut classes = 0;
 
-        eprintln!("IO: {:?}", io);
-        let mut map = init_map.clone();
-        for x in io.iter() {
-            match x.len() {
-                2 => {
-                    // 1
-                    let chars = x.chars().take(2).collect::<HashSet<_>>();
-                    intersect(&mut map.get_mut(&'c').unwrap(), &chars);
-                    intersect(&mut map.get_mut(&'f').unwrap(), &chars);
-                }
-                3 => {
-                    // 7
-                    let chars = x.chars().take(3).collect::<HashSet<_>>();
-                    intersect(&mut map.get_mut(&'a').unwrap(), &chars);
-                    intersect(&mut map.get_mut(&'c').unwrap(), &chars);
-                    intersect(&mut map.get_mut(&'f').unwrap(), &chars);
-                }
-                4 => {
-                    // 4
-                    let chars = x.chars().take(4).collect::<HashSet<_>>();
-                    intersect(&mut map.get_mut(&'b').unwrap(), &chars);
-                    intersect(&mut map.get_mut(&'c').unwrap(), &chars);
-                    intersect(&mut map.get_mut(&'d').unwrap(), &chars);
-                    intersect(&mut map.get_mut(&'f').unwrap(), &chars);
-                }
-                5 => {
-                    // 2, 5
-                    // Doesn't narrow it
-                }
-                6 => {
-                    // 0, 3, 6
-                    // Doesn't narrow it
-                }
-                7 => {
-                    // 8
-                    // Doesn't narrow it
-                }
-                _ => unreachable!("ahhh"),
+    loop {
+        let mut seg_ids = HashMap::new();
+        for (d, patterns) in displays.iter().enumerate() {
+            for (seg, colour) in seg_colours[d].iter_mut().enumerate() {
+                let mut lit_by = patterns
+                    .iter()
+                    .zip(glyph_colours[d].iter())
+                    .filter(|(&p, _)| p & 1 << seg != 0)
+                    .map(|(_, &c)| c)
+                    .collect::<Vec<_>>();
+                lit_by.sort_unstable();
+                let next = seg_ids.len();
+                *colour = *seg_ids.entry((*colour, lit_by)).or_insert(next);
             }
         }
 
-        let mut counts = map.iter().map(|(&k, v)| (k, v.len())).collect::<Vec<_>>();
-        counts.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-        let (chr, _count) = counts.iter().find(|(_chr, count)| *count > 1).unwrap();
+        let mut glyph_ids = HashMap::new();
+        for (d, patterns) in displays.iter().enumerate() {
+            for (g, &p) in patterns.iter().enumerate() {
+                let mut lights = (0..segments)
+                    .filter(|seg| p & 1 << seg != 0)
+                    .map(|seg| seg_colours[d][seg])
+                    .collect::<Vec<_>>();
+                lights.sort_unstable();
+                let next = glyph_ids.len();
+                glyph_colours[d][g] = *glyph_ids
+                    .entry((glyph_colours[d][g], lights))
+                    .or_insert(next);
+            }
+        }
+
+        // Every round splits classes or leaves them alone, so an unchanged count means stable
+        if seg_ids.len() + glyph_ids.len() == classes {
+            return seg_colours;
+        }
+        classes = seg_ids.len() + glyph_ids.len();
+    }
+}
 
-        let res = p2_rec(map.clone(), *chr, &io).unwrap();
+/// Scrambled patterns for each display
+type Displays = Vec<Vec<u32>>;
 
-        let digits = o
-            .iter()
-            .map(|oo| {
-                let tgt = oo
-                    .chars()
-                    .map(|c| *res.iter().find(|(_k, v)| **v == c).unwrap().0)
-                    .collect::<Vec<_>>();
-                identify_digit(&tgt)
-            })
-            .collect::<Vec<_>>();
-        let numeral: usize = digits
+fn parse_file(s: &str, glyphs: &GlyphSet) -> Result<(Displays, Displays), Box<dyn Error>> {
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for l in s.lines().filter(|l| !l.is_empty()) {
+        let (patterns, output) = l
+            .split_once('|')
+            .ok_or("missing | between patterns and output")?;
+        let masks = |s: &str| {
+            s.split_whitespace()
+                .map(|w| glyphs.mask(w))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        inputs.push(masks(patterns)?);
+        outputs.push(masks(output)?);
+    }
+
+    Ok((inputs, outputs))
+}
+
+fn p1(outputs: &[Vec<u32>], glyphs: &GlyphSet) -> usize {
+    outputs
+        .iter()
+        .flatten()
+        .filter(|m| glyphs.is_unique_length(m.count_ones())) // 1, 7, 4, 8
+        .count()
+}
+
+/// Maps each scrambled wire (by bit) to the segment it drives
+#[derive(Debug, PartialEq, Eq)]
+struct Wiring(Vec<u32>);
+
+impl Wiring {
+    /// Translate a scrambled pattern into canonical segments
+    fn translate(&self, pattern: u32) -> u32 {
+        self.0
             .iter()
-            .rev()
             .enumerate()
-            .map(|(i, d)| (10usize).pow(i as u32) * d.unwrap())
-            .sum();
-        eprintln!("{:?}", res);
-        eprintln!("{:?}", digits);
-        eprintln!("{:?}", numeral);
-        output_numerals.push(numeral);
+            .filter(|(wire, _)| pattern & 1 << wire != 0)
+            .fold(0, |acc, (_, seg)| acc | seg)
     }
+}
 
-    output_numerals.iter().sum()
+/// Decode every display's output
+fn decode_all(
+    inputs: &[Vec<u32>],
+    outputs: &[Vec<u32>],
+    glyphs: &GlyphSet,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    inputs
+        .iter()
+        .zip(outputs.iter())
+        .map(|(i, o)| glyphs.decode(&glyphs.deduce(i)?, o))
+        .collect()
+}
+
+fn p2(inputs: &[Vec<u32>], outputs: &[Vec<u32>], glyphs: &GlyphSet) -> usize {
+    decode_all(inputs, outputs, glyphs)
+        .unwrap()
+        .iter()
+        .map(|s| s.parse::<usize>().unwrap())
+        .sum()
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() != 2 && !(args.len() == 4 && args[2] == "--glyphs") {
+        panic!("usage: aoc_08 <input> [--glyphs FILE]");
     }
+    let glyphs = match args.get(3) {
+        Some(path) => std::fs::read_to_string(path)
+            .expect("couldn't read the glyph set")
+            .parse()
+            .expect("invalid glyph set"),
+        None => GlyphSet::default(),
+    };
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
-    let (inputs, outputs) = parse_file(&file_str);
-    let p1 = p1(&outputs);
+    let (inputs, outputs) = parse_file(&file_str, &glyphs).expect("couldn't parse the file");
+    let p1 = p1(&outputs, &glyphs);
     println!("p1: {}", p1);
-    let p2 = p2(&inputs, &outputs);
-    println!("p2: {}", p2);
+    if args.len() == 2 {
+        let p2 = p2(&inputs, &outputs, &glyphs);
+        println!("p2: {}", p2);
+    } else {
+        // Custom glyphs needn't be decimal digits, so just show what each display reads
+        for decoded in decode_all(&inputs, &outputs, &glyphs).expect("couldn't decode") {
+            println!("{}", decoded);
+        }
+    }
 }
 
+#[cfg(test)]
+#[path = "common/rng.rs"]
+mod rng;
+
 #[cfg(test)]
 mod test_day8 {
+    use super::rng::XorShift64;
     use super::*;
 
     #[test]
@@ -284,10 +377,126 @@ dbcfg fgd bdegcaf fgec aegbdf ecdfab fbedc dacgb gdcebf gf | cefg dcbef fcge gbc
 bdfegc cbegaf gecbf dfcage bdacg ed bedf ced adcbefg gebcd | ed bcgafe cdgba cbgef
 egadfb cdbfeg cegd fecab cgb gbdefca cg fgcdab egfdb bfceg | gbdfcae bgc cg cgb
 gcafb gcf dcaebfg ecagb gf abcdeg gaef cafbge fdbac fegbdc | fgae cfgab fg bagce"#;
-        let (inputs, outputs) = parse_file(&example);
-        let p1 = p1(&outputs);
-        let p2 = p2(&inputs, &outputs);
+        let glyphs = GlyphSet::default();
+        let (inputs, outputs) = parse_file(example, &glyphs).unwrap();
+        let p1 = p1(&outputs, &glyphs);
+        let p2 = p2(&inputs, &outputs, &glyphs);
         assert_eq!(p1, 26);
         assert_eq!(p2, 61229);
     }
+
+    #[test]
+    fn test_wiring() {
+        let glyphs = GlyphSet::default();
+        let (inputs, outputs) = parse_file(
+            "acedgfb cdfbe gcdfa fbcad dab cefabd cdfgeb eafb cagedb ab | cdfeb fcadb cdfeb cdbaf",
+            &glyphs,
+        )
+        .unwrap();
+        let wiring = glyphs.deduce(&inputs[0]).unwrap();
+        // d -> a, e -> b, a -> c, f -> d, g -> e, b -> f, c -> g
+        let expected = [2, 5, 6, 0, 1, 3, 4].iter().map(|seg| 1 << seg).collect();
+        assert_eq!(wiring, Wiring(expected));
+        assert_eq!(
+            glyphs.symbol(wiring.translate(glyphs.mask("ab").unwrap())),
+            Some('1')
+        );
+        assert_eq!(
+            glyphs.symbol(wiring.translate(glyphs.mask("ac").unwrap())),
+            None
+        );
+        assert_eq!(glyphs.decode(&wiring, &outputs[0]).unwrap(), "5353");
+
+        assert!(glyphs.deduce(&inputs[0][..9]).is_err());
+        let mut missing_one = inputs[0].clone();
+        missing_one[9] = glyphs.mask("abc").unwrap();
+        assert!(glyphs.deduce(&missing_one).is_err());
+        assert!(parse_file("ab cx | ab", &glyphs).is_err());
+    }
+
+    /// Scramble every glyph with a random wiring and check it's recovered
+    fn check_scrambled(glyphs: &GlyphSet, seed: u64) {
+        let mut rng = XorShift64::new(seed);
+        let n = glyphs.segments.len();
+        let mut perm = (0..n).collect::<Vec<_>>();
+        for i in (1..n).rev() {
+            perm.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+        // Segment `seg` is driven by wire `perm[seg]`
+        let scramble = |p: u32| {
+            (0..n)
+                .filter(|seg| p & 1 << seg != 0)
+                .fold(0, |acc, seg| acc | 1 << perm[seg])
+        };
+        let mut patterns = glyphs
+            .patterns()
+            .into_iter()
+            .map(scramble)
+            .collect::<Vec<_>>();
+        patterns.reverse();
+
+        let wiring = glyphs.deduce(&patterns).unwrap();
+        let symbols = glyphs.decode(&wiring, &patterns).unwrap();
+        assert_eq!(
+            symbols,
+            glyphs
+                .glyphs
+                .iter()
+                .rev()
+                .map(|&(s, _)| s)
+                .collect::<String>()
+        );
+    }
+
+    #[test]
+    fn test_glyph_sets() {
+        // Seven-segment hex digits
+        let hex = format!(
+            "# hex\n{}A: abcdef\nb: bdefg\nC: abeg\nd: cdefg\nE: abdeg\nF: abde\n",
+            SEVEN_SEGMENT
+        );
+        let hex: GlyphSet = hex.parse().unwrap();
+        assert_eq!(hex.len(), 16);
+        // 8 is the only glyph with all seven segments now that 4 shares its length with C
+        assert!(hex.is_unique_length(7));
+        assert!(!hex.is_unique_length(4));
+        for seed in 1..20 {
+            check_scrambled(&hex, seed);
+        }
+
+        // Fourteen-segment digits and a few letters
+        let fourteen: GlyphSet = "segments: abcdefghijklmn
+            0: abcdefjm
+            1: bcj
+            2: abdegh
+            3: abcdh
+            4: bcfgh
+            5: adfgl
+            6: acdefgh
+            7: ajl
+            8: abcdefgh
+            9: abcfgh
+            A: abcefgh
+            K: efgkn
+            M: bcefjk
+            N: bcefkn
+            X: jkmn
+            Z: adjm"
+            .parse()
+            .unwrap();
+        for seed in 1..20 {
+            check_scrambled(&fourteen, seed);
+        }
+
+        // Mirror images can't be told apart
+        let err = "segments: abc\n0: ab\n1: bc\n"
+            .parse::<GlyphSet>()
+            .unwrap_err();
+        assert!(err.to_string().contains("ambiguous"), "{}", err);
+        // Neither can segments that always light together
+        assert!("segments: abc\n0: abc\n1: a\n".parse::<GlyphSet>().is_err());
+        assert!("segments: ab\n0: a\n1: a\n".parse::<GlyphSet>().is_err());
+        assert!("segments: ab\n0: ax\n".parse::<GlyphSet>().is_err());
+        assert!("0: ab\n".parse::<GlyphSet>().is_err());
+    }
 }
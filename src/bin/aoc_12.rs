@@ -2,7 +2,12 @@
 
 use petgraph::graphmap::UnGraphMap;
 
-use std::env;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fmt::Write,
+};
 
 fn parse_file(s: &str) -> UnGraphMap<&str, ()> {
     let mut graph = UnGraphMap::<&str, ()>::new();
@@ -18,75 +23,412 @@ fn parse_file(s: &str) -> UnGraphMap<&str, ()> {
     graph
 }
 
-fn p1p2(graph: &UnGraphMap<&str, ()>, p2: bool) -> usize {
-    let mut traversal_stack = vec!["start"];
-    let mut trajectory = vec![];
-    let mut good_trajs = vec![];
-    while let Some(cur) = traversal_stack.pop() {
-        trajectory.push(cur);
-
-        // Goal Node
-        if cur == "end" {
-            good_trajs.push(trajectory.iter().map(|s| s.to_string()).collect::<Vec<_>>());
-            trajectory.pop();
-            continue;
-        } else if cur == "FINISH" {
-            trajectory.pop();
-            trajectory.pop();
-            continue;
+fn is_small(cave: &str) -> bool {
+    cave.chars().all(char::is_lowercase)
+}
+
+/// Which caves a path may enter and how often. Small caves can be entered
+/// once, plus however many of the shared `revisits` are left. Large caves can
+/// be entered any number of times.
+#[derive(Debug, Clone, Default)]
+struct RevisitPolicy<'a> {
+    /// Extra small cave visits allowed over the whole path
+    revisits: u32,
+    /// Caves that can be entered up to this many times, ignoring the rules
+    /// above and not drawing on `revisits`
+    visit_limits: HashMap<&'a str, u32>,
+    /// Caves a path can't enter
+    forbidden: HashSet<&'a str>,
+    /// Caves a path has to pass through
+    mandatory: HashSet<&'a str>,
+}
+
+impl<'a> RevisitPolicy<'a> {
+    /// Part 1: no small cave twice
+    fn no_revisits() -> Self {
+        Self::default()
+    }
+
+    /// Part 2: one small cave twice
+    fn single_revisit() -> Self {
+        Self {
+            revisits: 1,
+            ..Self::default()
         }
+    }
+}
 
-        // Push neighbors
-        traversal_stack.push("FINISH");
-        for (_, to_node, _) in graph.edges(cur) {
-            if p2 {
-                if to_node == "start" {
-                    continue;
-                }
+/// The cave graph with caves numbered, and a policy resolved against it
+struct CaveSystem<'a> {
+    names: Vec<&'a str>,
+    neighbours: Vec<Vec<usize>>,
+    start: usize,
+    end: usize,
+    revisits: u32,
+    /// Per cave, None for no limit
+    limits: Vec<Option<u32>>,
+    /// Per cave, whether entering again spends a revisit
+    small: Vec<bool>,
+    forbidden: Vec<bool>,
+    mandatory: Vec<bool>,
+    /// Caves whose visit count matters have a slot in the counts vector
+    slots: Vec<Option<usize>>,
+}
+
+impl<'a> CaveSystem<'a> {
+    fn new(
+        graph: &UnGraphMap<&'a str, ()>,
+        policy: &RevisitPolicy,
+    ) -> Result<Self, Box<dyn Error>> {
+        let names = graph.nodes().collect::<Vec<_>>();
+        let idx = |name: &str| {
+            names
+                .iter()
+                .position(|n| *n == name)
+                .ok_or_else(|| format!("unknown cave {}", name))
+        };
+
+        let mut limits = vec![None; names.len()];
+        let mut small = names.iter().map(|n| is_small(n)).collect::<Vec<_>>();
+        for (name, limit) in policy.visit_limits.iter() {
+            let cave = idx(name)?;
+            limits[cave] = Some(*limit);
+            small[cave] = false;
+        }
+        let mut forbidden = vec![false; names.len()];
+        for name in policy.forbidden.iter() {
+            forbidden[idx(name)?] = true;
+        }
+        let mut mandatory = vec![false; names.len()];
+        for name in policy.mandatory.iter() {
+            let cave = idx(name)?;
+            if forbidden[cave] {
+                Err(format!("cave {} is both forbidden and mandatory", name))?
+            }
+            mandatory[cave] = true;
+        }
+
+        let neighbours = names
+            .iter()
+            .map(|n| graph.neighbors(n).map(|n| idx(n).unwrap()).collect())
+            .collect::<Vec<Vec<_>>>();
+        let unbounded = |cave: usize| !small[cave] && limits[cave].is_none();
+        for (cave, neighs) in neighbours.iter().enumerate() {
+            if unbounded(cave) && neighs.iter().any(|n| unbounded(*n)) {
+                Err(format!(
+                    "unlimited cave {} connects to another, so there are infinitely many paths",
+                    names[cave]
+                ))?
+            }
+        }
+
+        let mut next_slot = 0;
+        let slots = (0..names.len())
+            .map(|cave| {
+                (small[cave] || limits[cave].is_some() || mandatory[cave]).then(|| {
+                    next_slot += 1;
+                    next_slot - 1
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            start: idx("start")?,
+            end: idx("end")?,
+            names,
+            neighbours,
+            revisits: policy.revisits,
+            limits,
+            small,
+            forbidden,
+            mandatory,
+            slots,
+        })
+    }
+
+    /// Whether a path that has entered `next` `count` times can enter it
+    /// again. Returns the revisits left afterwards.
+    fn enter(&self, next: usize, count: u32, revisits_left: u32) -> Option<u32> {
+        if next == self.start || self.forbidden[next] {
+            return None;
+        }
+        if let Some(limit) = self.limits[next] {
+            return (count < limit).then_some(revisits_left);
+        }
+        if self.small[next] && count > 0 {
+            return revisits_left.checked_sub(1);
+        }
+        Some(revisits_left)
+    }
 
-                if to_node.chars().all(char::is_lowercase)
-                    && trajectory.iter().rev().find(|t| *t == &to_node).is_some()
-                {
-                    let mut uniq = std::collections::HashSet::new();
-                    let already_has_dupe = trajectory
-                        .iter()
-                        .filter(|&x| x != &"start" && x.chars().all(char::is_lowercase))
-                        .any(|&x| !uniq.insert(x));
-
-                    if already_has_dupe {
-                        continue;
-                    }
+    fn count_of(&self, counts: &[u32], cave: usize) -> u32 {
+        self.slots[cave].map_or(0, |slot| counts[slot])
+    }
+
+    fn mandatory_done(&self, counts: &[u32]) -> bool {
+        (0..self.names.len()).all(|cave| !self.mandatory[cave] || self.count_of(counts, cave) > 0)
+    }
+
+    /// Number of paths from `cur` to the end, given how often each tracked
+    /// cave has been entered and how many revisits are left
+    fn count_from(
+        &self,
+        cur: usize,
+        counts: &mut Vec<u32>,
+        revisits_left: u32,
+        memo: &mut HashMap<(usize, u32, Vec<u32>), u64>,
+    ) -> u64 {
+        if cur == self.end {
+            return self.mandatory_done(counts) as u64;
+        }
+        let key = (cur, revisits_left, counts.clone());
+        if let Some(count) = memo.get(&key) {
+            return *count;
+        }
+
+        let mut count = 0;
+        for &next in self.neighbours[cur].iter() {
+            let left = match self.enter(next, self.count_of(counts, next), revisits_left) {
+                Some(left) => left,
+                None => continue,
+            };
+            match self.slots[next] {
+                Some(slot) => {
+                    counts[slot] += 1;
+                    count += self.count_from(next, counts, left, memo);
+                    counts[slot] -= 1;
                 }
-                traversal_stack.push(to_node);
-            } else {
-                if (to_node.chars().all(char::is_lowercase)
-                    && trajectory.iter().rev().find(|t| *t == &to_node).is_some())
-                    || to_node == "start"
-                {
+                None => count += self.count_from(next, counts, left, memo),
+            }
+        }
+
+        memo.insert(key, count);
+        count
+    }
+
+    fn initial_counts(&self) -> Vec<u32> {
+        let mut counts = vec![0; self.slots.iter().flatten().count()];
+        if let Some(slot) = self.slots[self.start] {
+            counts[slot] = 1;
+        }
+        counts
+    }
+
+    fn count_paths(&self) -> u64 {
+        let mut memo = HashMap::new();
+        let mut counts = self.initial_counts();
+        self.count_from(self.start, &mut counts, self.revisits, &mut memo)
+    }
+
+    /// Every path from start to end, depth first
+    fn paths(&self) -> Paths<'_, 'a> {
+        Paths {
+            caves: self,
+            stack: vec![(self.start, 0, self.revisits)],
+            counts: self.initial_counts(),
+        }
+    }
+}
+
+/// Lazily enumerates paths, see `CaveSystem::paths`
+struct Paths<'c, 'a> {
+    caves: &'c CaveSystem<'a>,
+    /// Caves on the current path, each with the index of its next neighbour
+    /// to try and the revisits left on arriving there
+    stack: Vec<(usize, usize, u32)>,
+    counts: Vec<u32>,
+}
+
+impl<'c, 'a> Paths<'c, 'a> {
+    fn push(&mut self, cave: usize, revisits_left: u32) {
+        if let Some(slot) = self.caves.slots[cave] {
+            self.counts[slot] += 1;
+        }
+        self.stack.push((cave, 0, revisits_left));
+    }
+
+    fn pop(&mut self) {
+        if let Some((cave, _, _)) = self.stack.pop() {
+            if let Some(slot) = self.caves.slots[cave] {
+                self.counts[slot] -= 1;
+            }
+        }
+    }
+}
+
+impl<'c, 'a> Iterator for Paths<'c, 'a> {
+    type Item = Vec<&'a str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let caves = self.caves;
+        while let Some((cur, cursor, revisits_left)) = self.stack.last_mut() {
+            let next = match caves.neighbours[*cur].get(*cursor) {
+                Some(next) => *next,
+                None => {
+                    self.pop();
                     continue;
                 }
-                traversal_stack.push(to_node);
+            };
+            *cursor += 1;
+
+            let count = caves.count_of(&self.counts, next);
+            let left = match caves.enter(next, count, *revisits_left) {
+                Some(left) => left,
+                None => continue,
+            };
+            self.push(next, left);
+
+            if next == caves.end {
+                let done = caves.mandatory_done(&self.counts);
+                let path = self.stack.iter().map(|(c, _, _)| caves.names[*c]).collect();
+                self.pop();
+                if done {
+                    return Some(path);
+                }
             }
         }
+        None
     }
-    good_trajs.sort();
-    for t in good_trajs.iter() {
-        eprintln!("{:?}", t);
+}
+
+/// Undirected edges are keyed with the names in order
+fn edge_key<'a>(a: &'a str, b: &'a str) -> (&'a str, &'a str) {
+    (a.min(b), a.max(b))
+}
+
+/// How many of the policy's paths use each corridor. Counted by enumerating
+/// them, so it's only as fast as listing every path.
+fn edge_traversals<'a>(caves: &CaveSystem<'a>) -> HashMap<(&'a str, &'a str), u64> {
+    let mut traversals = HashMap::new();
+    for path in caves.paths() {
+        for step in path.windows(2) {
+            *traversals.entry(edge_key(step[0], step[1])).or_insert(0) += 1;
+        }
     }
+    traversals
+}
 
-    good_trajs.len()
+/// Graphviz DOT for the cave graph. Start and end are double circles, large
+/// caves boxes and small caves plain circles. With `traversals`, edges are
+/// labelled with their count and drawn thicker the busier they are.
+fn to_dot(graph: &UnGraphMap<&str, ()>, traversals: Option<&HashMap<(&str, &str), u64>>) -> String {
+    let mut dot = String::from("graph caves {\n");
+    for cave in graph.nodes() {
+        let style = match cave {
+            "start" => "shape=doublecircle, style=filled, fillcolor=palegreen",
+            "end" => "shape=doublecircle, style=filled, fillcolor=lightcoral",
+            _ if is_small(cave) => "shape=circle",
+            _ => "shape=box, style=filled, fillcolor=lightblue",
+        };
+        writeln!(dot, "    \"{}\" [{}];", cave, style).unwrap();
+    }
+
+    let max = traversals
+        .and_then(|t| t.values().copied().max())
+        .unwrap_or(0)
+        .max(1);
+    for (from, to, _) in graph.all_edges() {
+        write!(dot, "    \"{}\" -- \"{}\"", from, to).unwrap();
+        if let Some(traversals) = traversals {
+            let count = traversals.get(&edge_key(from, to)).copied().unwrap_or(0);
+            let width = 1.0 + 7.0 * count as f64 / max as f64;
+            let style = if count == 0 { ", style=dashed" } else { "" };
+            write!(
+                dot,
+                " [label=\"{}\", penwidth={:.2}{}]",
+                count, width, style
+            )
+            .unwrap();
+        }
+        dot.push_str(";\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn p1p2(graph: &UnGraphMap<&str, ()>, policy: &RevisitPolicy) -> u64 {
+    CaveSystem::new(graph, policy)
+        .expect("invalid revisit policy")
+        .count_paths()
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() < 2 {
+        panic!(
+            "usage: aoc_12 <input> [--revisits N] [--limit cave=N] [--forbid cave] \
+             [--require cave] [--list] [--dot FILE] [--dot-paths FILE]"
+        );
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
     let graph = parse_file(&file_str);
-    println!("P1 {}", p1p2(&graph, false));
-    println!("P2 {}", p1p2(&graph, true));
+
+    // Any policy options describe a custom variant to solve alongside p1 and p2
+    let mut custom = None;
+    let mut list = false;
+    let mut dot_path = None;
+    let mut dot_paths_path = None;
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        if flag == "--list" {
+            list = true;
+            continue;
+        }
+        let value = flags
+            .next()
+            .unwrap_or_else(|| panic!("{} needs a value", flag))
+            .as_str();
+        match flag.as_str() {
+            "--dot" => {
+                dot_path = Some(value);
+                continue;
+            }
+            "--dot-paths" => {
+                dot_paths_path = Some(value);
+                continue;
+            }
+            _ => {}
+        }
+        let policy = custom.get_or_insert_with(RevisitPolicy::no_revisits);
+        match flag.as_str() {
+            "--revisits" => policy.revisits = value.parse().expect("bad revisit count"),
+            "--limit" => {
+                let (cave, limit) = value.split_once('=').expect("--limit wants cave=N");
+                let limit = limit.parse().expect("bad visit limit");
+                policy.visit_limits.insert(cave, limit);
+            }
+            "--forbid" => {
+                policy.forbidden.insert(value);
+            }
+            "--require" => {
+                policy.mandatory.insert(value);
+            }
+            other => panic!("unknown option {}", other),
+        }
+    }
+
+    println!("P1 {}", p1p2(&graph, &RevisitPolicy::no_revisits()));
+    println!("P2 {}", p1p2(&graph, &RevisitPolicy::single_revisit()));
+    if let Some(policy) = &custom {
+        println!("Custom {}", p1p2(&graph, policy));
+    }
+    // Listing and traversal counts use the custom policy if there is one
+    let policy = custom.unwrap_or_else(RevisitPolicy::single_revisit);
+    let caves = CaveSystem::new(&graph, &policy).expect("invalid revisit policy");
+    if list {
+        for path in caves.paths() {
+            println!("{}", path.join(","));
+        }
+    }
+    if let Some(dot_path) = dot_path {
+        std::fs::write(dot_path, to_dot(&graph, None)).expect("couldn't write the DOT file");
+    }
+    if let Some(dot_path) = dot_paths_path {
+        let traversals = edge_traversals(&caves);
+        std::fs::write(dot_path, to_dot(&graph, Some(&traversals)))
+            .expect("couldn't write the DOT file");
+    }
 }
 
 #[cfg(test)]
@@ -103,9 +445,122 @@ b-d
 A-end
 b-end
 "#;
-        let graph = parse_file(&example);
-        eprintln!("graph: {:?}", graph);
-        assert_eq!(p1p2(&graph, false), 10);
-        assert_eq!(p1p2(&graph, true), 36);
+        let graph = parse_file(example);
+        let p1 = RevisitPolicy::no_revisits();
+        let p2 = RevisitPolicy::single_revisit();
+        assert_eq!(p1p2(&graph, &p1), 10);
+        assert_eq!(p1p2(&graph, &p2), 36);
+
+        let caves = CaveSystem::new(&graph, &p1).unwrap();
+        let mut paths = caves.paths().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths.len(), 10);
+        assert_eq!(paths[0], vec!["start", "A", "b", "A", "c", "A", "end"]);
+        assert_eq!(CaveSystem::new(&graph, &p2).unwrap().paths().count(), 36);
+
+        // Every policy should agree between counting and enumerating
+        let mut policies = vec![];
+        let mut two_revisits = RevisitPolicy::no_revisits();
+        two_revisits.revisits = 2;
+        policies.push(two_revisits);
+        let mut b_thrice = RevisitPolicy::no_revisits();
+        b_thrice.visit_limits.insert("b", 3);
+        policies.push(b_thrice);
+        let mut a_once = RevisitPolicy::single_revisit();
+        a_once.visit_limits.insert("A", 1);
+        policies.push(a_once);
+        let mut no_b = RevisitPolicy::single_revisit();
+        no_b.forbidden.insert("b");
+        policies.push(no_b);
+        let mut via_c_and_d = RevisitPolicy::single_revisit();
+        via_c_and_d.mandatory.insert("c");
+        via_c_and_d.mandatory.insert("d");
+        policies.push(via_c_and_d);
+        for policy in policies.iter() {
+            let caves = CaveSystem::new(&graph, policy).unwrap();
+            assert_eq!(caves.count_paths(), caves.paths().count() as u64);
+        }
+
+        let mut no_b = RevisitPolicy::no_revisits();
+        no_b.forbidden.insert("b");
+        let caves = CaveSystem::new(&graph, &no_b).unwrap();
+        let mut paths = caves.paths().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                vec!["start", "A", "c", "A", "end"],
+                vec!["start", "A", "end"]
+            ]
+        );
+
+        let mut via_d = RevisitPolicy::no_revisits();
+        via_d.mandatory.insert("d");
+        let caves = CaveSystem::new(&graph, &via_d).unwrap();
+        assert_eq!(caves.count_paths(), 0);
+        via_d.revisits = 1;
+        let caves = CaveSystem::new(&graph, &via_d).unwrap();
+        assert!(caves.count_paths() > 0);
+        assert!(caves.paths().all(|p| p.contains(&"d")));
+
+        // Limits well past what fits in a byte
+        let bounce = parse_file("start-A\nA-B\nB-end\n");
+        let mut many = RevisitPolicy::no_revisits();
+        many.visit_limits.insert("A", 300);
+        many.visit_limits.insert("B", 300);
+        let caves = CaveSystem::new(&bounce, &many).unwrap();
+        assert_eq!(caves.count_paths(), 300);
+        assert_eq!(caves.paths().count(), 300);
+
+        let mut unknown = RevisitPolicy::no_revisits();
+        unknown.forbidden.insert("zz");
+        assert!(CaveSystem::new(&graph, &unknown).is_err());
+        let mut conflicting = RevisitPolicy::no_revisits();
+        conflicting.forbidden.insert("c");
+        conflicting.mandatory.insert("c");
+        assert!(CaveSystem::new(&graph, &conflicting).is_err());
+
+        let traversals = edge_traversals(&CaveSystem::new(&graph, &p1).unwrap());
+        // Every path leaves start and reaches end exactly once
+        assert_eq!(
+            traversals[&("A", "start")] + traversals[&("b", "start")],
+            10
+        );
+        assert_eq!(traversals[&("A", "end")] + traversals[&("b", "end")], 10);
+        // d is a dead end without a revisit
+        assert_eq!(traversals.get(&("b", "d")), None);
+        let dot = to_dot(&graph, Some(&traversals));
+        assert!(dot.starts_with("graph caves {\n"));
+        assert!(dot.contains("\"start\" [shape=doublecircle"));
+        assert!(dot.contains("\"A\" [shape=box"));
+        assert!(dot.contains("\"c\" [shape=circle];"));
+        assert!(dot.contains("[label=\"0\", penwidth=1.00, style=dashed]"));
+        assert_eq!(dot.matches(" -- ").count(), 7);
+        assert!(!to_dot(&graph, None).contains("label"));
+
+        let larger = r#"fs-end
+he-DX
+fs-he
+start-DX
+pj-DX
+end-zg
+zg-sl
+zg-pj
+pj-he
+RW-he
+fs-DX
+pj-RW
+zg-RW
+start-pj
+he-WI
+zg-he
+pj-fs
+start-RW
+"#;
+        let graph = parse_file(larger);
+        assert_eq!(p1p2(&graph, &p1), 226);
+        assert_eq!(p1p2(&graph, &p2), 3509);
+        let caves = CaveSystem::new(&graph, &p2).unwrap();
+        assert_eq!(caves.paths().count(), 3509);
     }
 }
@@ -1,12 +1,16 @@
 //! AoC 2021 - 14
 
-use std::{collections::HashMap, convert::TryInto, env};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fmt,
+};
 
-type RulesTable = HashMap<[char; 2], char>;
-type FreqTable = HashMap<char, usize>;
-type MemoTable = HashMap<([char; 2], usize), FreqTable>;
+/// Insertion rule `ab -> c`, kept as a list so the validator can see duplicates
+type Rule = ([char; 2], char);
 
-fn parse_file(s: &str) -> (Vec<char>, RulesTable) {
+fn parse_file(s: &str) -> (Vec<char>, Vec<Rule>) {
     let mut blank_split = s.split("\n\n");
     let template_iter = blank_split.next().unwrap();
     let rules_iter = blank_split.next().unwrap();
@@ -14,8 +18,7 @@ fn parse_file(s: &str) -> (Vec<char>, RulesTable) {
     let template = template_iter
         .lines()
         .filter(|l| !l.is_empty())
-        .map(|l| l.chars())
-        .flatten()
+        .flat_map(|l| l.chars())
         .collect();
 
     let rules = rules_iter
@@ -23,10 +26,10 @@ fn parse_file(s: &str) -> (Vec<char>, RulesTable) {
         .filter(|l| !l.is_empty())
         .map(|l| {
             let mut delim = l.split(" -> ");
-            let src = delim.next().unwrap();
-            let src_arr = [src.chars().nth(0).unwrap(), src.chars().nth(1).unwrap()];
+            let mut src = delim.next().unwrap().chars();
+            let src_arr = [src.next().unwrap(), src.next().unwrap()];
 
-            let tgt = delim.next().unwrap().chars().nth(0).unwrap();
+            let tgt = delim.next().unwrap().chars().next().unwrap();
             (src_arr, tgt)
         })
         .collect();
@@ -34,138 +37,479 @@ fn parse_file(s: &str) -> (Vec<char>, RulesTable) {
     (template, rules)
 }
 
-fn step(poly: &[char], rules: &RulesTable) -> Vec<char> {
-    let mut new_poly = Vec::new();
-    for c in poly.windows(2) {
-        let c_arr: [char; 2] = c.try_into().unwrap();
-        new_poly.push(c[0]);
+/// Problems found in a rule table by `validate`
+#[derive(Debug, PartialEq, Eq)]
+enum RuleIssue {
+    /// A pair with no rule that can turn up in the polymer
+    Missing { pair: [char; 2] },
+    /// The same rule listed more than once
+    Duplicate { pair: [char; 2], insert: char },
+    /// Two rules inserting different elements for the same pair
+    Conflicting {
+        pair: [char; 2],
+        first: char,
+        second: char,
+    },
+    /// An element used by the rules that isn't in the template
+    NotInTemplate { c: char },
+}
 
-        new_poly.push(rules[&c_arr]);
+impl fmt::Display for RuleIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleIssue::Missing { pair } => {
+                write!(f, "no rule for pair {}{}", pair[0], pair[1])
+            }
+            RuleIssue::Duplicate { pair, insert } => {
+                write!(f, "rule {}{} -> {} is repeated", pair[0], pair[1], insert)
+            }
+            RuleIssue::Conflicting {
+                pair,
+                first,
+                second,
+            } => write!(
+                f,
+                "pair {}{} inserts both {} and {}",
+                pair[0], pair[1], first, second
+            ),
+            RuleIssue::NotInTemplate { c } => {
+                write!(f, "element {} is in the rules but not the template", c)
+            }
+        }
     }
-    new_poly.push(*poly.last().unwrap());
-
-    new_poly
 }
 
-fn run_steps(poly: &[char], rules: &RulesTable, steps: usize) -> Vec<char> {
-    let mut poly = poly.to_vec();
-    for _i in 0..steps {
-        poly = step(&poly, rules);
+/// Check a rule table against its template. Missing pairs are only reported if they can
+/// actually occur, following the rules from the template's pairs and leaving unmatched pairs
+/// as they are.
+fn validate(template: &[char], rules: &[Rule]) -> Vec<RuleIssue> {
+    let mut issues = Vec::new();
+
+    let mut table: HashMap<[char; 2], Vec<char>> = HashMap::new();
+    for &(pair, insert) in rules.iter() {
+        let inserts = table.entry(pair).or_default();
+        match inserts.first() {
+            Some(&first) if first == insert => {
+                issues.push(RuleIssue::Duplicate { pair, insert });
+            }
+            Some(&first) if !inserts.contains(&insert) => issues.push(RuleIssue::Conflicting {
+                pair,
+                first,
+                second: insert,
+            }),
+            _ => {}
+        }
+        inserts.push(insert);
     }
-    poly
+
+    let mut seen = HashSet::new();
+    for &(pair, insert) in rules.iter() {
+        for c in [pair[0], pair[1], insert] {
+            if !template.contains(&c) && seen.insert(c) {
+                issues.push(RuleIssue::NotInTemplate { c });
+            }
+        }
+    }
+
+    let mut reached = template
+        .windows(2)
+        .map(|w| [w[0], w[1]])
+        .collect::<HashSet<_>>();
+    let mut stack = reached.iter().copied().collect::<Vec<_>>();
+    let mut missing = Vec::new();
+    while let Some(pair) = stack.pop() {
+        match table.get(&pair) {
+            Some(inserts) => {
+                for &c in inserts.iter() {
+                    for next in [[pair[0], c], [c, pair[1]]] {
+                        if reached.insert(next) {
+                            stack.push(next);
+                        }
+                    }
+                }
+            }
+            None => missing.push(pair),
+        }
+    }
+    missing.sort_unstable();
+    issues.extend(missing.into_iter().map(|pair| RuleIssue::Missing { pair }));
+
+    issues
 }
 
-fn p1(poly: &Vec<char>, rules: &RulesTable, steps: usize) -> usize {
-    let result_poly = run_steps(poly, rules, steps);
-    let mut freq = HashMap::new();
-    for c in result_poly.iter() {
-        *freq.entry(c).or_insert(0usize) += 1;
+/// What to do with pairs that have no insertion rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unmatched {
+    /// Refuse to build the polymer
+    Reject,
+    /// Leave the pair as it is
+    Keep,
+}
+
+/// Most steps `Polymer::element_at` will tabulate pair lengths for before they stop growing
+const GROWTH_LIMIT: u64 = 1 << 16;
+
+#[derive(Debug, PartialEq, Eq)]
+enum PolymerError {
+    /// A count no longer fits in a u128 somewhere on the way to `steps`
+    Overflow { steps: u64 },
+    /// The rule table has conflicts, or missing rules when they're rejected
+    InvalidRules(Vec<RuleIssue>),
+    /// Pair lengths were still short of the position after `GROWTH_LIMIT` steps, which only
+    /// happens when kept unmatched pairs make the polymer grow slowly
+    SlowGrowth { steps: u64 },
+}
+
+impl fmt::Display for PolymerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolymerError::Overflow { steps } => {
+                write!(f, "counts overflow a u128 within {} steps", steps)
+            }
+            PolymerError::InvalidRules(issues) => {
+                write!(f, "invalid rules: ")?;
+                for (i, issue) in issues.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", issue)?;
+                }
+                Ok(())
+            }
+            PolymerError::SlowGrowth { steps } => write!(
+                f,
+                "the polymer grows too slowly to follow for {} steps (limit {})",
+                steps, GROWTH_LIMIT
+            ),
+        }
     }
+}
 
-    let most_common = freq
-        .iter()
-        .max_by(|(_, va), (_, vb)| va.partial_cmp(&vb).unwrap())
-        .unwrap();
-    let least_common = freq
-        .iter()
-        .min_by(|(_, va), (_, vb)| va.partial_cmp(&vb).unwrap())
-        .unwrap();
+impl Error for PolymerError {}
 
-    most_common.1 - least_common.1
+/// Square matrix of counts where every operation checks for overflow
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Matrix {
+    n: usize,
+    data: Vec<u128>,
 }
 
-fn p2_recur(
-    poly: [char; 2],
-    rules: &RulesTable,
-    freq: &mut FreqTable,
-    memo: &mut MemoTable,
-    steps: usize,
-    limit: usize,
-) -> FreqTable {
-    // No new additions
-    if steps > limit {
-        return FreqTable::new();
+impl Matrix {
+    fn zeros(n: usize) -> Self {
+        Self {
+            n,
+            data: vec![0; n * n],
+        }
+    }
+
+    fn get(&self, row: usize, col: usize) -> u128 {
+        self.data[row * self.n + col]
+    }
+
+    fn get_mut(&mut self, row: usize, col: usize) -> &mut u128 {
+        &mut self.data[row * self.n + col]
     }
 
-    // If we've seen this "dimer" at this distance from the limit, then just return the cached
-    // frequencies
-    if let Some(table) = memo.get(&(poly, limit - steps)) {
-        return table.clone();
+    fn checked_mul(&self, other: &Matrix) -> Option<Matrix> {
+        let mut res = Matrix::zeros(self.n);
+        for row in 0..self.n {
+            for k in (0..self.n).filter(|&k| self.get(row, k) != 0) {
+                let a = self.get(row, k);
+                for col in 0..self.n {
+                    let prod = a.checked_mul(other.get(k, col))?;
+                    let cell = res.get_mut(row, col);
+                    *cell = cell.checked_add(prod)?;
+                }
+            }
+        }
+        Some(res)
     }
 
-    // otherwise ...
+    fn checked_apply(&self, v: &[u128]) -> Option<Vec<u128>> {
+        (0..self.n)
+            .map(|row| {
+                v.iter().enumerate().try_fold(0u128, |acc, (col, &x)| {
+                    acc.checked_add(self.get(row, col).checked_mul(x)?)
+                })
+            })
+            .collect()
+    }
+}
 
-    // get the new mer
-    let new_mer = rules[&poly];
+/// Polymer template and rules as a linear system over pair counts. Each step maps pair `ab` with
+/// rule `ab -> c` to one `ac` and one `cb`, so the counts after N steps are `T^N v` for the
+/// template's pair counts `v`, which takes O(log N) matrix products.
+struct Polymer {
+    template: Vec<char>,
+    /// Every element in the template or rules, pair `ab` has index `a * len + b`
+    elements: Vec<char>,
+    transition: Matrix,
+    /// The pairs (by index) each pair expands into, `None` for pairs left unchanged
+    children: Vec<Option<(usize, usize)>>,
+}
 
-    // build the first new pair
-    let first_pair = [poly[0], new_mer];
-    // get the frequency contributions from that pair
-    let mut result_table = p2_recur(first_pair, rules, freq, memo, steps + 1, limit);
+impl Polymer {
+    fn new(template: &[char], rules: &[Rule], unmatched: Unmatched) -> Result<Self, PolymerError> {
+        let fatal = validate(template, rules)
+            .into_iter()
+            .filter(|issue| match issue {
+                RuleIssue::Conflicting { .. } => true,
+                RuleIssue::Missing { .. } => unmatched == Unmatched::Reject,
+                _ => false,
+            })
+            .collect::<Vec<_>>();
+        if !fatal.is_empty() {
+            return Err(PolymerError::InvalidRules(fatal));
+        }
 
-    // build the first new pair
-    let second_pair = [new_mer, poly[1]];
-    // get the frequency contributions from that pair
-    let second_table = p2_recur(second_pair, rules, freq, memo, steps + 1, limit);
+        let mut elements = template.to_vec();
+        elements.extend(rules.iter().flat_map(|&(src, tgt)| [src[0], src[1], tgt]));
+        elements.sort_unstable();
+        elements.dedup();
+
+        let n = elements.len();
+        let mut polymer = Self {
+            template: template.to_vec(),
+            elements,
+            transition: Matrix::zeros(n * n),
+            children: vec![None; n * n],
+        };
+        // Duplicates are harmless once conflicts are ruled out
+        let table = rules.iter().copied().collect::<HashMap<_, _>>();
+        for from in 0..n * n {
+            let src = polymer.pair(from);
+            match table.get(&src) {
+                Some(&tgt) => {
+                    let left = polymer.pair_index([src[0], tgt]).unwrap();
+                    let right = polymer.pair_index([tgt, src[1]]).unwrap();
+                    *polymer.transition.get_mut(left, from) += 1;
+                    *polymer.transition.get_mut(right, from) += 1;
+                    polymer.children[from] = Some((left, right));
+                }
+                None => *polymer.transition.get_mut(from, from) += 1,
+            }
+        }
+        Ok(polymer)
+    }
 
-    // merge the resulting ccontributions
-    for (k, v) in second_table.iter() {
-        *result_table.entry(*k).or_insert(0) += v;
+    fn element_index(&self, c: char) -> Option<usize> {
+        self.elements.binary_search(&c).ok()
     }
-    // and add the new mer
-    *result_table.entry(new_mer).or_insert(0) += 1;
 
-    // cache it
-    memo.insert((poly, limit - steps), result_table.clone());
+    fn pair_index(&self, pair: [char; 2]) -> Option<usize> {
+        Some(self.element_index(pair[0])? * self.elements.len() + self.element_index(pair[1])?)
+    }
 
-    result_table
-}
+    fn pair(&self, index: usize) -> [char; 2] {
+        let n = self.elements.len();
+        [self.elements[index / n], self.elements[index % n]]
+    }
 
-fn p2(poly: &[char], rules: &RulesTable, limit: usize) -> usize {
-    let mut freq = HashMap::new();
-    let mut memo = MemoTable::new();
-    for c in poly.windows(2) {
-        let c_arr: [char; 2] = c.try_into().unwrap();
-        *freq.entry(c[0]).or_insert(0usize) += 1;
+    /// Count of each pair (by index) after `steps` steps
+    fn pair_counts(&self, steps: u64) -> Result<Vec<u128>, PolymerError> {
+        let mut counts = vec![0u128; self.transition.n];
+        for w in self.template.windows(2) {
+            counts[self.pair_index([w[0], w[1]]).unwrap()] += 1;
+        }
 
-        let freq_table = p2_recur(c_arr, rules, &mut freq, &mut memo, 1, limit);
-        for (k, v) in freq_table.iter() {
-            *freq.entry(*k).or_insert(0) += v;
+        let overflow = || PolymerError::Overflow { steps };
+        let mut power = self.transition.clone();
+        let mut remaining = steps;
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                counts = power.checked_apply(&counts).ok_or_else(overflow)?;
+            }
+            remaining >>= 1;
+            if remaining > 0 {
+                power = power.checked_mul(&power).ok_or_else(overflow)?;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// How often each element occurs after `steps` steps. Every element starts exactly one pair
+    /// except the last, which never changes.
+    fn element_counts(&self, steps: u64) -> Result<Vec<(char, u128)>, PolymerError> {
+        let mut counts = self
+            .elements
+            .iter()
+            .map(|&c| (c, 0u128))
+            .collect::<Vec<_>>();
+        if let Some(&last) = self.template.last() {
+            counts[self.element_index(last).unwrap()].1 += 1;
+        }
+        for (i, &count) in self.pair_counts(steps)?.iter().enumerate() {
+            let first = &mut counts[self.element_index(self.pair(i)[0]).unwrap()].1;
+            *first = first
+                .checked_add(count)
+                .ok_or(PolymerError::Overflow { steps })?;
         }
+        Ok(counts)
     }
-    *freq.entry(*poly.last().unwrap()).or_insert(0usize) += 1;
 
-    let most_common = freq
-        .iter()
-        .max_by(|(_, va), (_, vb)| va.partial_cmp(&vb).unwrap())
-        .unwrap();
-    let least_common = freq
-        .iter()
-        .min_by(|(_, va), (_, vb)| va.partial_cmp(&vb).unwrap())
-        .unwrap();
+    /// How many times `pair` occurs after `steps` steps
+    fn pair_count(&self, pair: [char; 2], steps: u64) -> Result<u128, PolymerError> {
+        match self.pair_index(pair) {
+            Some(i) => Ok(self.pair_counts(steps)?[i]),
+            None => Ok(0),
+        }
+    }
 
-    most_common.1 - least_common.1
+    /// The element at `position` after `steps` steps, or `None` past the end.
+    ///
+    /// Descends from the template's pairs into whichever child holds the position, using the
+    /// number of elements (less the last) each pair expands to. Those lengths only matter up to
+    /// `position + 1`, so capped there they stop changing after about log(position) steps when
+    /// every pair has a rule. Above that the descent depends only on the current pair and offset,
+    /// so a repeated state skips ahead by whole cycles.
+    ///
+    /// With `Unmatched::Keep` the lengths can grow as slowly as one per step, which would need a
+    /// table row per step, so that case fails once it needs more than `GROWTH_LIMIT` rows.
+    fn element_at(&self, position: u128, steps: u64) -> Result<Option<char>, PolymerError> {
+        let cap = position.saturating_add(1);
+        let mut lengths = vec![vec![1u128; self.children.len()]];
+        while (lengths.len() as u64) <= steps {
+            let prev = lengths.last().unwrap();
+            let next = self
+                .children
+                .iter()
+                .map(|c| match c {
+                    Some((left, right)) => prev[*left].saturating_add(prev[*right]).min(cap),
+                    None => 1,
+                })
+                .collect::<Vec<_>>();
+            if next == *prev {
+                break;
+            }
+            if lengths.len() as u64 > GROWTH_LIMIT {
+                return Err(PolymerError::SlowGrowth { steps });
+            }
+            lengths.push(next);
+        }
+        let stable = lengths.len() as u64 - 1;
+        let len = |pair: usize, k: u64| lengths[k.min(stable) as usize][pair];
+
+        let mut pos = position;
+        let mut pair = None;
+        for w in self.template.windows(2) {
+            let p = self.pair_index([w[0], w[1]]).unwrap();
+            if pos < len(p, steps) {
+                pair = Some(p);
+                break;
+            }
+            pos -= len(p, steps);
+        }
+        let mut pair = match pair {
+            Some(p) => p,
+            None if pos == 0 => return Ok(self.template.last().copied()),
+            None => return Ok(None),
+        };
+
+        let mut k = steps;
+        let mut seen = HashMap::new();
+        while let (true, Some((left, right))) = (k > 0, self.children[pair]) {
+            if k > stable {
+                if let Some(prev_k) = seen.insert((pair, pos), k) {
+                    let cycle = prev_k - k;
+                    k -= (k - stable) / cycle * cycle;
+                    seen.clear();
+                    continue;
+                }
+            }
+            if pos < len(left, k - 1) {
+                pair = left;
+            } else {
+                pos -= len(left, k - 1);
+                pair = right;
+            }
+            k -= 1;
+        }
+        Ok(Some(self.pair(pair)[0]))
+    }
+}
+
+/// Most common minus least common element count after `steps` steps, only counting elements that
+/// are present.
+fn spread(polymer: &Polymer, steps: u64) -> Result<u128, PolymerError> {
+    let counts = polymer.element_counts(steps)?;
+    let present = counts.iter().map(|&(_, n)| n).filter(|&n| n > 0);
+    let most_common = present.clone().max().unwrap_or(0);
+    let least_common = present.min().unwrap_or(0);
+    Ok(most_common - least_common)
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() < 2 {
+        panic!(
+            "usage: aoc_14 <input> [steps] [--keep-unmatched] [--validate] [--pair AB] [--at K]"
+        );
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
     let (template, rules) = parse_file(&file_str);
-    println!("P1: {}", p1(&template, &rules, 10));
-    println!("P2: {}", p2(&template, &rules, 40));
+
+    let mut unmatched = Unmatched::Reject;
+    let mut steps = None;
+    let mut report = false;
+    let mut pair = None;
+    let mut position = None;
+    let mut args_iter = args[2..].iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--keep-unmatched" => unmatched = Unmatched::Keep,
+            "--validate" => report = true,
+            "--pair" => {
+                let mut chars = args_iter.next().expect("--pair needs a pair").chars();
+                pair = Some([chars.next().unwrap(), chars.next().unwrap()]);
+            }
+            "--at" => {
+                let k = args_iter.next().expect("--at needs a position");
+                position = Some(k.parse::<u128>().expect("position should be a number"));
+            }
+            _ => steps = Some(arg.parse::<u64>().expect("steps should be a number")),
+        }
+    }
+
+    if report {
+        for issue in validate(&template, &rules) {
+            println!("{}", issue);
+        }
+    }
+    let polymer = Polymer::new(&template, &rules, unmatched).unwrap_or_else(|e| panic!("{}", e));
+    println!("P1: {}", spread(&polymer, 10).unwrap());
+    println!("P2: {}", spread(&polymer, 40).unwrap());
+    if let Some(steps) = steps {
+        match polymer.element_counts(steps) {
+            Ok(counts) => {
+                println!("{} steps: {}", steps, spread(&polymer, steps).unwrap());
+                for (c, n) in counts {
+                    println!("{}: {}", c, n);
+                }
+            }
+            Err(e) => println!("{} steps: {}", steps, e),
+        }
+        if let Some(pair) = pair {
+            match polymer.pair_count(pair, steps) {
+                Ok(n) => println!("{}{}: {}", pair[0], pair[1], n),
+                Err(e) => println!("{}{}: {}", pair[0], pair[1], e),
+            }
+        }
+        if let Some(position) = position {
+            match polymer.element_at(position, steps) {
+                Ok(Some(c)) => println!("at {}: {}", position, c),
+                Ok(None) => println!("at {}: past the end", position),
+                Err(e) => println!("at {}: {}", position, e),
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test_day14 {
     use super::*;
 
-    #[test]
-    fn test() {
-        let example = r#"NNCB
+    const EXAMPLE: &str = r#"NNCB
 
 CH -> B
 HH -> N
@@ -183,11 +527,168 @@ BB -> N
 BC -> B
 CC -> N
 CN -> C"#;
-        let (template, rules) = parse_file(&example);
-        eprintln!("{:?}", template);
-        eprintln!("{:?}", rules);
-        assert_eq!(p1(&template, &rules, 10), 1588);
-        assert_eq!(p2(&template, &rules, 10), 1588);
-        assert_eq!(p2(&template, &rules, 40), 2188189693529);
+
+    /// Builds the polymer string, only feasible for a few steps
+    fn run_steps(poly: &[char], rules: &[Rule], steps: usize) -> Vec<char> {
+        let rules = rules.iter().copied().collect::<HashMap<_, _>>();
+        let mut poly = poly.to_vec();
+        for _i in 0..steps {
+            let mut new_poly = Vec::new();
+            for c in poly.windows(2) {
+                new_poly.push(c[0]);
+                if let Some(&insert) = rules.get(&[c[0], c[1]]) {
+                    new_poly.push(insert);
+                }
+            }
+            new_poly.push(*poly.last().unwrap());
+            poly = new_poly;
+        }
+        poly
+    }
+
+    #[test]
+    fn test() {
+        let (template, rules) = parse_file(EXAMPLE);
+        let polymer = Polymer::new(&template, &rules, Unmatched::Reject).unwrap();
+        assert_eq!(spread(&polymer, 10), Ok(1588));
+        assert_eq!(spread(&polymer, 40), Ok(2188189693529));
+    }
+
+    #[test]
+    fn test_matrix() {
+        let (template, rules) = parse_file(EXAMPLE);
+        let polymer = Polymer::new(&template, &rules, Unmatched::Reject).unwrap();
+        for steps in 0..12 {
+            let poly = run_steps(&template, &rules, steps);
+            let counts = polymer.element_counts(steps as u64).unwrap();
+            for (c, n) in counts {
+                assert_eq!(poly.iter().filter(|&&p| p == c).count() as u128, n);
+            }
+        }
+
+        // The length doubles (less one) every step until it no longer fits
+        for steps in [0, 1, 50, 100, 125] {
+            let len: u128 = polymer
+                .element_counts(steps)
+                .unwrap()
+                .iter()
+                .map(|&(_, n)| n)
+                .sum();
+            assert_eq!(len, 3 * (1 << steps) + 1);
+        }
+        assert_eq!(
+            spread(&polymer, 1_000_000_000_000),
+            Err(PolymerError::Overflow {
+                steps: 1_000_000_000_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let (template, rules) = parse_file(EXAMPLE);
+        // H is only ever inserted, which is fine
+        assert_eq!(
+            validate(&template, &rules),
+            vec![RuleIssue::NotInTemplate { c: 'H' }]
+        );
+        assert!(Polymer::new(&template, &rules, Unmatched::Reject).is_ok());
+
+        let (template, rules) = parse_file("NNCB\n\nNN -> C\nNC -> X\nNN -> C\nCB -> H\nCB -> N\n");
+        assert_eq!(
+            validate(&template, &rules),
+            vec![
+                RuleIssue::Duplicate {
+                    pair: ['N', 'N'],
+                    insert: 'C'
+                },
+                RuleIssue::Conflicting {
+                    pair: ['C', 'B'],
+                    first: 'H',
+                    second: 'N'
+                },
+                RuleIssue::NotInTemplate { c: 'X' },
+                RuleIssue::NotInTemplate { c: 'H' },
+                RuleIssue::Missing { pair: ['C', 'H'] },
+                RuleIssue::Missing { pair: ['C', 'N'] },
+                RuleIssue::Missing { pair: ['H', 'B'] },
+                RuleIssue::Missing { pair: ['N', 'B'] },
+                RuleIssue::Missing { pair: ['N', 'X'] },
+                RuleIssue::Missing { pair: ['X', 'C'] },
+            ]
+        );
+        match Polymer::new(&template, &rules, Unmatched::Keep) {
+            Err(PolymerError::InvalidRules(issues)) => assert_eq!(issues.len(), 1),
+            _ => panic!("conflicting rules should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_unmatched() {
+        // Only NN and NC have rules, everything else is left alone
+        let (template, rules) = parse_file("NNCB\n\nNN -> C\nNC -> B\n");
+        assert!(Polymer::new(&template, &rules, Unmatched::Reject).is_err());
+        let polymer = Polymer::new(&template, &rules, Unmatched::Keep).unwrap();
+        for steps in 0..8 {
+            let poly = run_steps(&template, &rules, steps);
+            for (c, n) in polymer.element_counts(steps as u64).unwrap() {
+                assert_eq!(poly.iter().filter(|&&p| p == c).count() as u128, n);
+            }
+        }
+
+        // A table without rules leaves the template unchanged however many steps are run
+        let (template, rules) = parse_file("NNCB\n\n");
+        let polymer = Polymer::new(&template, &rules, Unmatched::Keep).unwrap();
+        assert_eq!(
+            polymer.element_counts(1_000_000_000_000),
+            Ok(vec![('B', 1), ('C', 1), ('N', 2)])
+        );
+    }
+
+    #[test]
+    fn test_queries() {
+        let (template, rules) = parse_file(EXAMPLE);
+        let polymer = Polymer::new(&template, &rules, Unmatched::Reject).unwrap();
+        for steps in 0..8 {
+            let poly = run_steps(&template, &rules, steps);
+            for (i, &c) in poly.iter().enumerate() {
+                assert_eq!(polymer.element_at(i as u128, steps as u64), Ok(Some(c)));
+            }
+            assert_eq!(
+                polymer.element_at(poly.len() as u128, steps as u64),
+                Ok(None)
+            );
+            for w in poly.windows(2) {
+                let count = poly.windows(2).filter(|x| x == &w).count() as u128;
+                assert_eq!(polymer.pair_count([w[0], w[1]], steps as u64), Ok(count));
+            }
+        }
+        assert_eq!(polymer.pair_count(['Z', 'Z'], 10), Ok(0));
+        // NNCB becomes NCNBCHB
+        assert_eq!(polymer.element_at(1, 1), Ok(Some('C')));
+        assert_eq!(polymer.element_at(5, 1), Ok(Some('H')));
+
+        // Every step keeps the old elements at the even positions
+        for (i, steps) in [(5u128, 40u64), (12345, 1_000_000_000_000)] {
+            let c = polymer.element_at(i, steps);
+            assert!(matches!(c, Ok(Some(_))));
+            assert_eq!(polymer.element_at(i << 60, steps + 60), c);
+        }
+        assert_eq!(polymer.element_at(0, 1_000_000_000_000), Ok(Some('N')));
+
+        // Keeping unmatched pairs AB only grows by one A per step, as AA never changes
+        let (template, rules) = parse_file("AB\n\nAB -> A\n");
+        let polymer = Polymer::new(&template, &rules, Unmatched::Keep).unwrap();
+        let steps = 10_000;
+        assert_eq!(polymer.element_at(0, steps), Ok(Some('A')));
+        assert_eq!(polymer.element_at(steps as u128, steps), Ok(Some('A')));
+        assert_eq!(polymer.element_at(steps as u128 + 1, steps), Ok(Some('B')));
+        assert_eq!(polymer.element_at(steps as u128 + 2, steps), Ok(None));
+        assert_eq!(
+            polymer.element_at(1_000_000_000_000_000, 1_000_000_000),
+            Err(PolymerError::SlowGrowth {
+                steps: 1_000_000_000
+            })
+        );
     }
 }
@@ -9,21 +9,127 @@ fn parse_file(s: &str) -> na::DMatrix<i64> {
     let char_iter = s
         .lines()
         .filter(|l| !l.is_empty())
-        .map(|l| l.chars().map(|c| c.to_string().parse::<i64>().unwrap()))
-        .flatten();
+        .flat_map(|l| l.chars().map(|c| c.to_string().parse::<i64>().unwrap()));
 
     na::DMatrix::<i64>::from_iterator(cols, rows, char_iter).transpose()
 }
 
+/// A rectangular grid where entering a cell costs its risk
+trait RiskGrid {
+    fn nrows(&self) -> usize;
+    fn ncols(&self) -> usize;
+    fn risk(&self, loc: (usize, usize)) -> i64;
+    /// Lowest risk of any cell, used to scale the heuristic
+    fn min_risk(&self) -> i64;
+}
+
+impl RiskGrid for na::DMatrix<i64> {
+    fn nrows(&self) -> usize {
+        self.nrows()
+    }
+    fn ncols(&self) -> usize {
+        self.ncols()
+    }
+    fn risk(&self, loc: (usize, usize)) -> i64 {
+        self[loc]
+    }
+    fn min_risk(&self) -> i64 {
+        self.min()
+    }
+}
+
+/// The base map repeated `times` in each direction, with risk going up by one
+/// (wrapping 9 back to 1) for every tile right or down. Risks are computed on
+/// demand rather than stored.
+struct TiledRiskMap<'a> {
+    base: &'a na::DMatrix<i64>,
+    times: usize,
+    min_risk: i64,
+}
+
+impl<'a> TiledRiskMap<'a> {
+    fn new(base: &'a na::DMatrix<i64>, times: usize) -> Self {
+        // Tile offsets repeat every 9, so there's no need to look further
+        let max_offset = (2 * (times - 1)).min(8);
+        let min_risk = base
+            .iter()
+            .flat_map(|v| (0..=max_offset).map(move |offset| Self::wrap(*v, offset)))
+            .min()
+            .unwrap();
+        Self {
+            base,
+            times,
+            min_risk,
+        }
+    }
+
+    fn wrap(base: i64, offset: usize) -> i64 {
+        (base + offset as i64 - 1) % 9 + 1
+    }
+}
+
+impl<'a> RiskGrid for TiledRiskMap<'a> {
+    fn nrows(&self) -> usize {
+        self.base.nrows() * self.times
+    }
+    fn ncols(&self) -> usize {
+        self.base.ncols() * self.times
+    }
+    fn risk(&self, (r, c): (usize, usize)) -> i64 {
+        let (rows, cols) = self.base.shape();
+        let base = self.base[(r % rows, c % cols)];
+        Self::wrap(base, r / rows + c / cols)
+    }
+    fn min_risk(&self) -> i64 {
+        self.min_risk
+    }
+}
+
+/// Which neighbouring cells can be stepped to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Movement {
+    Four,
+    Eight,
+}
+
+impl Movement {
+    fn offsets(&self) -> &'static [(i64, i64)] {
+        match self {
+            Movement::Four => &[(-1, 0), (0, -1), (0, 1), (1, 0)],
+            Movement::Eight => &[
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+        }
+    }
+
+    /// Fewest steps between two cells, ignoring risk
+    fn steps(&self, a: (usize, usize), b: (usize, usize)) -> i64 {
+        let dr = (a.0 as i64 - b.0 as i64).abs();
+        let dc = (a.1 as i64 - b.1 as i64).abs();
+        match self {
+            Movement::Four => dr + dc,
+            Movement::Eight => dr.max(dc),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 struct SearchNode {
     heur_cost: i64,
+    cost: i64,
     location: (usize, usize),
 }
 
 impl PartialOrd for SearchNode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.heur_cost.cmp(&other.heur_cost).reverse())
+        Some(self.cmp(other))
     }
 }
 impl Ord for SearchNode {
@@ -32,72 +138,59 @@ impl Ord for SearchNode {
     }
 }
 
-fn replicate_map(map: &na::DMatrix<i64>, times: usize) -> na::DMatrix<i64> {
-    let rows = map.nrows();
-    let cols = map.ncols();
-    let new_rows = rows * times;
-    let new_cols = rows * times;
+/// Cheapest route found by `astar`. The start cell's risk isn't counted.
+#[derive(Debug, PartialEq, Eq)]
+struct Path {
+    cost: i64,
+    cells: Vec<(usize, usize)>,
+}
 
-    let mut new_map = na::DMatrix::<i64>::zeros(new_rows, new_cols);
-    new_map.slice_mut((0, 0), (rows, cols)).copy_from(&map);
-    for c in 1..times {
-        let mat_left_col = (c - 1) * cols;
-        let mut src_mat = new_map.slice((0, mat_left_col), (rows, cols)).into_owned();
-        for v in src_mat.iter_mut() {
-            *v = if *v >= 9 { 1 } else { *v + 1 };
-        }
-        new_map
-            .slice_mut((0, c * cols), (rows, cols))
-            .copy_from(&src_mat);
-    }
-    for r in 1..times {
-        let row_above = (r - 1) * rows;
-        let row = (r) * rows;
-        for c in 0..times {
-            let col = c * cols;
-            let mut src_mat = new_map.slice((row_above, col), (rows, cols)).into_owned();
-            for v in src_mat.iter_mut() {
-                *v = if *v >= 9 { 1 } else { *v + 1 };
-            }
-            new_map
-                .slice_mut((row, col), (rows, cols))
-                .copy_from(&src_mat);
-        }
+/// A* from `start` to `goal`. Every step costs at least the grid's minimum
+/// risk, so fewest-steps times that never overestimates. None if the goal
+/// can't be reached.
+fn astar<G: RiskGrid>(
+    grid: &G,
+    start: (usize, usize),
+    goal: (usize, usize),
+    movement: Movement,
+) -> Option<Path> {
+    let rows = grid.nrows();
+    let cols = grid.ncols();
+    if start.0 >= rows || start.1 >= cols || goal.0 >= rows || goal.1 >= cols {
+        return None;
     }
+    let idx = |loc: (usize, usize)| loc.0 * cols + loc.1;
+    let offsets = movement.offsets();
+    let step_cost = grid.min_risk().max(0);
+    let heuristic = |loc| movement.steps(loc, goal) * step_cost;
 
-    new_map
-}
-
-fn astar(mat: &na::DMatrix<i64>) -> i64 {
+    // Flat storage, and the step we arrived by rather than the full cell, to
+    // keep big grids small
+    let mut cost_map = vec![i64::MAX; rows * cols];
+    let mut from_map = vec![u8::MAX; rows * cols];
     let mut open_set = BinaryHeap::<SearchNode>::new();
-    let mut from_map =
-        na::DMatrix::<(usize, usize)>::repeat(mat.nrows(), mat.ncols(), (usize::MAX, usize::MAX));
-    let mut cost_map = na::DMatrix::<i64>::repeat(mat.nrows(), mat.ncols(), i64::MAX);
-    *cost_map.get_mut((0, 0)).unwrap() = 0;
-
+    cost_map[idx(start)] = 0;
     open_set.push(SearchNode {
-        heur_cost: mat[(0, 0)],
-        location: (0, 0),
+        heur_cost: heuristic(start),
+        cost: 0,
+        location: start,
     });
 
-    let rows = mat.nrows();
-    let cols = mat.nrows();
-    let target = (rows - 1, cols - 1);
-
     while let Some(SearchNode {
-        heur_cost: _cur_heur_cost,
+        cost: cur_cost,
         location: cur_loc,
+        ..
     }) = open_set.pop()
     {
-        if cur_loc == target {
+        if cur_loc == goal {
             break;
         }
+        if cur_cost > cost_map[idx(cur_loc)] {
+            // Stale entry, we've since found a cheaper way here
+            continue;
+        }
 
-        let cur_cost = cost_map[cur_loc];
-
-        const NEIGH_OFFSETS: [(i64, i64); 4] = [(-1, 0), (0, -1), (0, 1), (1, 0)];
-
-        for (or, oc) in NEIGH_OFFSETS {
+        for (dir, (or, oc)) in offsets.iter().enumerate() {
             let nr = or + cur_loc.0 as i64;
             let nc = oc + cur_loc.1 as i64;
 
@@ -106,48 +199,73 @@ fn astar(mat: &na::DMatrix<i64>) -> i64 {
             }
 
             let neigh = (nr as usize, nc as usize);
-            let estimated_cost = cur_cost + mat[neigh];
-            if estimated_cost < cost_map[neigh] {
-                from_map[neigh] = cur_loc;
-                cost_map[neigh] = estimated_cost;
-                let heuristic = 0; //(((rows - neigh.0) + (cols - neigh.1)) * 10) as i64;
+            let new_cost = cur_cost + grid.risk(neigh);
+            if new_cost < cost_map[idx(neigh)] {
+                from_map[idx(neigh)] = dir as u8;
+                cost_map[idx(neigh)] = new_cost;
                 open_set.push(SearchNode {
-                    heur_cost: estimated_cost + heuristic,
+                    heur_cost: new_cost + heuristic(neigh),
+                    cost: new_cost,
                     location: neigh,
                 });
             }
         }
     }
 
-    eprintln!("map: {}", cost_map[target]);
+    if cost_map[idx(goal)] == i64::MAX {
+        return None;
+    }
 
-    let mut traj = Vec::new();
-    let mut cur = target;
-    while cur != (0, 0) {
-        traj.push(cur);
-        cur = from_map[cur];
+    let mut cells = vec![goal];
+    let mut cur = goal;
+    while cur != start {
+        let (or, oc) = offsets[from_map[idx(cur)] as usize];
+        cur = ((cur.0 as i64 - or) as usize, (cur.1 as i64 - oc) as usize);
+        cells.push(cur);
     }
-    traj.push((0, 0));
-    traj.reverse();
-    eprintln!("traj:{:?}", traj);
+    cells.reverse();
 
-    let vals = traj.iter().map(|rc| mat[*rc]).collect::<Vec<_>>();
-    eprintln!("vals:{:?}", vals);
+    Some(Path {
+        cost: cost_map[idx(goal)],
+        cells,
+    })
+}
 
-    cost_map[target]
+/// Top left to bottom right, the way the puzzle wants it
+fn lowest_risk<G: RiskGrid>(grid: &G, movement: Movement) -> i64 {
+    let goal = (grid.nrows() - 1, grid.ncols() - 1);
+    astar(grid, (0, 0), goal, movement)
+        .expect("the goal is always reachable")
+        .cost
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() < 2 {
+        panic!("usage: aoc_15 <input> [--diagonal] [--tiles N]");
+    }
+    // --diagonal allows 8-connected movement, --tiles sets the part 2 tiling
+    let mut movement = Movement::Four;
+    let mut tiles = 5;
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--diagonal" => movement = Movement::Eight,
+            "--tiles" => {
+                tiles = flags
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .expect("--tiles needs a positive number")
+            }
+            other => panic!("unknown option {}", other),
+        }
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
     let map = parse_file(&file_str);
-    let p1_total = astar(&map);
-    let big_map = replicate_map(&map, 5);
-    let p2_total = astar(&big_map);
+    let p1_total = lowest_risk(&map, movement);
+    let p2_total = lowest_risk(&TiledRiskMap::new(&map, tiles), movement);
     println!("P1: {}", p1_total);
     println!("P2: {}", p2_total);
 }
@@ -169,12 +287,55 @@ mod test_day15 {
 1293138521
 2311944581"#;
 
-        let map = parse_file(&example);
-        let p1_total = astar(&map);
-        let big_map = replicate_map(&map, 5);
-        let p2_total = astar(&big_map);
+        let map = parse_file(example);
+        let movement = Movement::Four;
+        let p1_total = lowest_risk(&map, movement);
+        let big_map = TiledRiskMap::new(&map, 5);
+        let p2_total = lowest_risk(&big_map, movement);
 
         assert_eq!(p1_total, 40);
         assert_eq!(p2_total, 315);
+        assert_eq!((big_map.nrows(), big_map.ncols()), (50, 50));
+        assert_eq!(big_map.min_risk(), 1);
+        let first_row = "11637517422274862853338597396444961841755517295286";
+        let last_row = "67554889357866599146897761125791887223681299833479";
+        for (c, (first, last)) in first_row.chars().zip(last_row.chars()).enumerate() {
+            assert_eq!(big_map.risk((0, c)), first.to_digit(10).unwrap() as i64);
+            assert_eq!(big_map.risk((49, c)), last.to_digit(10).unwrap() as i64);
+        }
+
+        let path = astar(&map, (0, 0), (9, 9), Movement::Four).unwrap();
+        assert_eq!(path.cells.len(), 19);
+        assert_eq!(path.cells[0], (0, 0));
+        assert_eq!(
+            path.cells.iter().skip(1).map(|rc| map[*rc]).sum::<i64>(),
+            40
+        );
+
+        // Non-square, and somewhere other than the corners
+        let wide = parse_file("19111\n11191\n99991");
+        assert_eq!(lowest_risk(&wide, Movement::Four), 8);
+        let path = astar(&wide, (2, 4), (0, 0), Movement::Four).unwrap();
+        assert_eq!(path.cost, 8);
+        assert_eq!(
+            path.cells,
+            vec![
+                (2, 4),
+                (1, 4),
+                (0, 4),
+                (0, 3),
+                (0, 2),
+                (1, 2),
+                (1, 1),
+                (1, 0),
+                (0, 0)
+            ]
+        );
+
+        // Diagonals can cut corners
+        let path = astar(&wide, (0, 0), (2, 4), Movement::Eight).unwrap();
+        assert_eq!(path.cost, 5);
+        assert_eq!(path.cells.len(), 6);
+        assert_eq!(astar(&wide, (0, 0), (3, 0), Movement::Four), None);
     }
 }
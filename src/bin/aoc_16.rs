@@ -1,6 +1,6 @@
 //! AoC 2021 - 16
 
-use std::env;
+use std::{env, fmt};
 
 #[derive(Debug, PartialEq, Eq)]
 struct Packet {
@@ -20,115 +20,263 @@ enum Payload {
     Operator(Operator),
 }
 
-const BIG_END_HEX: [[u8; 4]; 16] = [
-    [0, 0, 0, 0],
-    [0, 0, 0, 1],
-    [0, 0, 1, 0],
-    [0, 0, 1, 1],
-    [0, 1, 0, 0],
-    [0, 1, 0, 1],
-    [0, 1, 1, 0],
-    [0, 1, 1, 1],
-    [1, 0, 0, 0],
-    [1, 0, 0, 1],
-    [1, 0, 1, 0],
-    [1, 0, 1, 1],
-    [1, 1, 0, 0],
-    [1, 1, 0, 1],
-    [1, 1, 1, 0],
-    [1, 1, 1, 1],
-];
-
-fn assemble<const B: usize>(bits: &[u8]) -> u64 {
-    let mut x: u64 = 0;
-    for (i, exp) in (0..B).rev().enumerate() {
-        x += (bits[i] as u64) << exp;
-    }
-    x
-}
-
-fn parse_packet(stream: &[u8]) -> (Packet, usize) {
-    let mut i = 0;
-    let version = assemble::<3>(stream);
-    i += 3;
-
-    let packet_type = assemble::<3>(&stream[i..]);
-    i += 3;
-    let packet: Packet;
+/// Things that can go wrong while decoding a transmission. Offsets are in bits
+/// from the start of the transmission.
+#[derive(Debug, PartialEq, Eq)]
+enum DecodeError {
+    InvalidHex {
+        index: usize,
+        c: char,
+    },
+    Truncated {
+        offset: usize,
+        wanted: usize,
+    },
+    TruncatedHeader {
+        offset: usize,
+    },
+    LiteralOverflow {
+        offset: usize,
+    },
+    EmptyOperator {
+        offset: usize,
+    },
+    SubpacketLengthMismatch {
+        offset: usize,
+        declared: usize,
+        actual: usize,
+    },
+    InvalidOperandCount {
+        offset: usize,
+        operator: u8,
+        count: usize,
+    },
+    NonZeroPadding {
+        offset: usize,
+    },
+}
 
-    if packet_type == 4 {
-        let mut literal = [0u8; 64];
-        let mut count = 0;
-        loop {
-            let literal_chunk_with_continuation = &stream[i..i + 5];
-            i += 5;
-            literal[count * 4..(count + 1) * 4]
-                .clone_from_slice(&literal_chunk_with_continuation[1..5]);
-            count += 1;
-            if literal_chunk_with_continuation[0] == 0 {
-                break;
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::InvalidHex { index, c } => {
+                write!(f, "invalid hex digit {:?} at char {}", c, index)
             }
-        }
-        let bit_count = count * 4;
-        literal.rotate_right(64 - bit_count);
-
-        let literal = assemble::<64>(&literal[..]);
-        packet = Packet {
-            version: version as u8,
-            payload: Payload::Literal(literal),
-        };
-    } else {
-        let mut subpackets = Vec::<Packet>::new();
-        let length_id = stream[i];
-        i += 1;
-        if length_id == 0 {
-            let length_bits = assemble::<15>(&stream[i..]);
-            i += 15;
-            assert!(length_bits != 0);
-            let mut total_bits = 0;
-            loop {
-                let (subpacket, bits) = parse_packet(&stream[i..]);
-                i += bits;
-                total_bits += bits;
-                subpackets.push(subpacket);
-                if total_bits >= length_bits as usize {
-                    break;
-                }
+            DecodeError::Truncated { offset, wanted } => {
+                write!(
+                    f,
+                    "truncated at bit {}: wanted {} more bits",
+                    offset, wanted
+                )
+            }
+            DecodeError::TruncatedHeader { offset } => {
+                write!(f, "truncated packet header at bit {}", offset)
+            }
+            DecodeError::LiteralOverflow { offset } => {
+                write!(f, "literal starting at bit {} exceeds 64 bits", offset)
+            }
+            DecodeError::EmptyOperator { offset } => {
+                write!(f, "operator at bit {} has no sub-packets", offset)
             }
-        } else {
-            let length_packets = assemble::<11>(&stream[i..]);
-            i += 11;
-            for _p in 0..length_packets {
-                let (subpacket, bits) = parse_packet(&stream[i..]);
-                i += bits;
-                subpackets.push(subpacket);
+            DecodeError::SubpacketLengthMismatch {
+                offset,
+                declared,
+                actual,
+            } => write!(
+                f,
+                "operator at bit {} declares {} bits of sub-packets but they span {}",
+                offset, declared, actual
+            ),
+            DecodeError::InvalidOperandCount {
+                offset,
+                operator,
+                count,
+            } => write!(
+                f,
+                "comparison operator {} at bit {} needs exactly 2 operands, got {}",
+                operator, offset, count
+            ),
+            DecodeError::NonZeroPadding { offset } => {
+                write!(f, "non-zero padding bit at bit {}", offset)
             }
         }
-        packet = Packet {
-            version: version as u8,
-            payload: Payload::Operator(Operator {
-                operator: packet_type as u8,
-                subpackets,
-            }),
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A sum or product operator whose value doesn't fit in a u64
+#[derive(Debug, PartialEq, Eq)]
+struct ValueOverflow {
+    operator: u8,
+}
+
+impl fmt::Display for ValueOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.operator == 0 { "sum" } else { "product" };
+        write!(f, "{} overflows a u64", name)
+    }
+}
+
+impl std::error::Error for ValueOverflow {}
+
+/// MSB-first reader over packed bytes
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    /// Bits consumed so far
+    fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() * 8 - self.pos
+    }
+
+    /// Read `n` (<= 64) bits as a big-endian unsigned integer
+    fn read_bits(&mut self, n: usize) -> Result<u64, DecodeError> {
+        assert!(n <= 64);
+        if n > self.remaining() {
+            return Err(DecodeError::Truncated {
+                offset: self.pos,
+                wanted: n,
+            });
+        }
+        let mut x: u64 = 0;
+        let mut left = n;
+        while left > 0 {
+            let byte = self.bytes[self.pos / 8];
+            let bit_in_byte = self.pos % 8;
+            let take = left.min(8 - bit_in_byte);
+            let chunk = (byte >> (8 - bit_in_byte - take)) & ((1u16 << take) - 1) as u8;
+            x = (x << take) | chunk as u64;
+            self.pos += take;
+            left -= take;
+        }
+        Ok(x)
+    }
+}
+
+fn parse_literal(reader: &mut BitReader) -> Result<u64, DecodeError> {
+    let start = reader.position();
+    let mut literal: u64 = 0;
+    let mut bit_count = 0;
+    loop {
+        let more = reader.read_bits(1)?;
+        let chunk = reader.read_bits(4)?;
+        // Leading zero chunks don't contribute to the width
+        if literal != 0 || chunk != 0 {
+            bit_count += 4;
+        }
+        if bit_count > 64 {
+            return Err(DecodeError::LiteralOverflow { offset: start });
+        }
+        literal = (literal << 4) | chunk;
+        if more == 0 {
+            break;
+        }
+    }
+    Ok(literal)
+}
+
+fn parse_packet(reader: &mut BitReader) -> Result<Packet, DecodeError> {
+    let offset = reader.position();
+    let (version, packet_type) = match (reader.read_bits(3), reader.read_bits(3)) {
+        (Ok(version), Ok(packet_type)) => (version as u8, packet_type as u8),
+        _ => return Err(DecodeError::TruncatedHeader { offset }),
+    };
+
+    if packet_type == 4 {
+        return Ok(Packet {
+            version,
+            payload: Payload::Literal(parse_literal(reader)?),
+        });
+    }
+
+    let mut subpackets = Vec::<Packet>::new();
+    let length_id = reader.read_bits(1)?;
+    if length_id == 0 {
+        let length_bits = reader.read_bits(15)? as usize;
+        let start = reader.position();
+        while reader.position() - start < length_bits {
+            subpackets.push(parse_packet(reader)?);
+        }
+        let actual = reader.position() - start;
+        if actual != length_bits {
+            return Err(DecodeError::SubpacketLengthMismatch {
+                offset,
+                declared: length_bits,
+                actual,
+            });
+        }
+    } else {
+        let length_packets = reader.read_bits(11)?;
+        for _p in 0..length_packets {
+            subpackets.push(parse_packet(reader)?);
         }
     }
 
-    (packet, i)
+    if subpackets.is_empty() {
+        return Err(DecodeError::EmptyOperator { offset });
+    }
+    // gt, lt and eq
+    if (5..=7).contains(&packet_type) && subpackets.len() != 2 {
+        return Err(DecodeError::InvalidOperandCount {
+            offset,
+            operator: packet_type,
+            count: subpackets.len(),
+        });
+    }
+
+    Ok(Packet {
+        version,
+        payload: Payload::Operator(Operator {
+            operator: packet_type,
+            subpackets,
+        }),
+    })
 }
 
-fn parse_file(s: &str) -> Packet {
-    let bit_stream = s
+/// Pack hex digits two to a byte. An odd trailing digit fills the high nibble.
+fn hex_to_bytes(s: &str) -> Result<Vec<u8>, DecodeError> {
+    let nibbles = s
         .chars()
-        .filter(|c| *c != '\n')
-        .map(|c| {
-            BIG_END_HEX[usize::from_str_radix(&c.to_string(), 16).unwrap()]
-                .iter()
-                .copied()
+        .filter(|c| !c.is_whitespace())
+        .enumerate()
+        .map(|(index, c)| {
+            c.to_digit(16)
+                .map(|d| d as u8)
+                .ok_or(DecodeError::InvalidHex { index, c })
         })
-        .flatten()
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(nibbles
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+        .collect())
+}
+
+fn parse_file(s: &str) -> Result<Packet, DecodeError> {
+    let bytes = hex_to_bytes(s)?;
+    let mut reader = BitReader::new(&bytes);
+    let packet = parse_packet(&mut reader)?;
 
-    parse_packet(&bit_stream).0
+    // Anything after the outermost packet must be zero padding
+    while reader.remaining() > 0 {
+        let offset = reader.position();
+        if reader.read_bits(1)? != 0 {
+            return Err(DecodeError::NonZeroPadding { offset });
+        }
+    }
+
+    Ok(packet)
 }
 
 fn p1(p: &Packet) -> usize {
@@ -141,7 +289,7 @@ fn p1(p: &Packet) -> usize {
             Payload::Literal(_) => {}
             Payload::Operator(op) => {
                 for sp in op.subpackets.iter() {
-                    stack.push(&sp);
+                    stack.push(sp);
                 }
             }
         }
@@ -150,15 +298,28 @@ fn p1(p: &Packet) -> usize {
     version_sum
 }
 
-fn p2(p: &Packet) -> u64 {
-    match &p.payload {
+fn p2(p: &Packet) -> Result<u64, ValueOverflow> {
+    Ok(match &p.payload {
         Payload::Literal(i) => *i,
         Payload::Operator(op) => {
-            let operands = op.subpackets.iter().map(|sp| p2(sp)).collect::<Vec<_>>();
+            let operands = op
+                .subpackets
+                .iter()
+                .map(p2)
+                .collect::<Result<Vec<_>, _>>()?;
+            let overflow = ValueOverflow {
+                operator: op.operator,
+            };
             if op.operator == 0 {
-                operands.iter().sum::<u64>()
+                operands
+                    .iter()
+                    .try_fold(0u64, |acc, &x| acc.checked_add(x))
+                    .ok_or(overflow)?
             } else if op.operator == 1 {
-                operands.iter().product::<u64>()
+                operands
+                    .iter()
+                    .try_fold(1u64, |acc, &x| acc.checked_mul(x))
+                    .ok_or(overflow)?
             } else if op.operator == 2 {
                 operands.iter().copied().min().unwrap()
             } else if op.operator == 3 {
@@ -185,7 +346,7 @@ fn p2(p: &Packet) -> u64 {
                 panic!("invalid op");
             }
         }
-    }
+    })
 }
 
 fn main() {
@@ -195,42 +356,159 @@ fn main() {
         panic!("There should be one argument");
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
-    let packet = parse_file(&file_str);
+    let packet = parse_file(&file_str).expect("couldn't decode the transmission");
     let p1 = p1(&packet);
     println!("p1: {}", p1);
-    let p2 = p2(&packet);
-    println!("p2: {}", p2);
+    match p2(&packet) {
+        Ok(p2) => println!("p2: {}", p2),
+        Err(e) => println!("p2: {}", e),
+    }
 }
 
+#[cfg(test)]
+#[path = "common/rng.rs"]
+mod rng;
+
 #[cfg(test)]
 mod test_day16 {
+    use super::rng::XorShift64;
     use super::*;
 
     #[test]
     fn test() {
         {
             let example = "D2FE28";
-            parse_file(&example);
+            parse_file(example).unwrap();
         }
         {
             let example = "38006F45291200";
-            parse_file(&example);
+            parse_file(example).unwrap();
         }
         {
             let example = "EE00D40C823060";
-            parse_file(&example);
+            parse_file(example).unwrap();
         }
         {
             let example = "8A004A801A8002F478";
-            assert_eq!(p1(&parse_file(&example)), 16);
+            assert_eq!(p1(&parse_file(example).unwrap()), 16);
         }
         {
             let example = "C0015000016115A2E0802F182340";
-            assert_eq!(p1(&parse_file(&example)), 23);
+            assert_eq!(p1(&parse_file(example).unwrap()), 23);
         }
         {
             let example = "A0016C880162017C3686B18A3D4780";
-            assert_eq!(p1(&parse_file(&example)), 31);
+            assert_eq!(p1(&parse_file(example).unwrap()), 31);
+        }
+        {
+            let example = "9C0141080250320F1802104A08";
+            assert_eq!(p2(&parse_file(example).unwrap()), Ok(1));
+        }
+        {
+            let literal = |i| Packet {
+                version: 0,
+                payload: Payload::Literal(i),
+            };
+            let sum = Packet {
+                version: 0,
+                payload: Payload::Operator(Operator {
+                    operator: 0,
+                    subpackets: vec![literal(u64::MAX), literal(1)],
+                }),
+            };
+            assert_eq!(p2(&sum), Err(ValueOverflow { operator: 0 }));
+            let product = Packet {
+                version: 0,
+                payload: Payload::Operator(Operator {
+                    operator: 1,
+                    subpackets: vec![literal(1 << 32), literal(1 << 32)],
+                }),
+            };
+            assert_eq!(p2(&product), Err(ValueOverflow { operator: 1 }));
+        }
+    }
+
+    #[test]
+    fn test_bit_reader() {
+        let bytes = [0b1010_1100, 0b0101_0011];
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert_eq!(reader.read_bits(10).unwrap(), 0b01100_01010);
+        assert_eq!(reader.position(), 13);
+        assert_eq!(
+            reader.read_bits(4),
+            Err(DecodeError::Truncated {
+                offset: 13,
+                wanted: 4
+            })
+        );
+        assert_eq!(reader.read_bits(3).unwrap(), 0b011);
+
+        // Literal of sixteen 0xF nibbles followed by 0x1: 68 bits
+        assert!(matches!(
+            parse_file("D3FFFFFFFFFFFFFFFFFFFC2"),
+            Err(DecodeError::LiteralOverflow { offset: 6 })
+        ));
+        assert!(matches!(
+            parse_file("D2FE"),
+            Err(DecodeError::Truncated { offset: 16, .. })
+        ));
+        assert!(matches!(
+            parse_file("D2XE28"),
+            Err(DecodeError::InvalidHex { index: 2, c: 'X' })
+        ));
+    }
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(
+            parse_file("D2FE29"),
+            Err(DecodeError::NonZeroPadding { offset: 23 })
+        );
+        assert_eq!(
+            parse_file(""),
+            Err(DecodeError::TruncatedHeader { offset: 0 })
+        );
+        // Operator declaring 0 bits of sub-packets
+        assert_eq!(
+            parse_file("380000"),
+            Err(DecodeError::EmptyOperator { offset: 0 })
+        );
+        // 38006F45291200 with the declared length shortened from 27 to 26
+        assert_eq!(
+            parse_file("38006B45291200"),
+            Err(DecodeError::SubpacketLengthMismatch {
+                offset: 0,
+                declared: 26,
+                actual: 27
+            })
+        );
+        // EE00D40C823060 (max of 3 literals) relabelled as gt
+        assert_eq!(
+            parse_file("F600D40C823060"),
+            Err(DecodeError::InvalidOperandCount {
+                offset: 0,
+                operator: 5,
+                count: 3
+            })
+        );
+    }
+
+    /// Feeds random hex strings to the decoder. It should only ever return an
+    /// error, never panic.
+    #[test]
+    fn fuzz_random_hex() {
+        let mut rng = XorShift64::new(0x2021_1216_dead_beef);
+
+        for _ in 0..20_000 {
+            let len = rng.below(64) as usize;
+            let hex = (0..len)
+                .map(|_| std::char::from_digit(rng.below(16) as u32, 16).unwrap())
+                .collect::<String>();
+            if let Ok(packet) = parse_file(&hex) {
+                p1(&packet);
+                let _ = p2(&packet);
+            }
         }
     }
 }
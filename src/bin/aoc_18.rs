@@ -1,79 +1,331 @@
 //! AoC 2021 - 18
 
-use std::env;
+use std::{env, error::Error, fmt, iter::Sum, ops::Add, str::FromStr};
 
+/// A snailfish number flattened to its regular numbers in reading order. Each
+/// is paired with its depth, i.e. the number of pairs that enclose it.
 #[derive(Debug, Eq, PartialEq, Clone)]
-enum SnailNumInner {
-    SnailNum(Box<SnailNum>),
-    Number(i64),
+struct SnailNum(Vec<(i64, u8)>);
+
+/// A single reduction action
+#[derive(Debug, Eq, PartialEq)]
+enum Step {
+    Explode(i64, i64),
+    Split(i64),
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
-struct SnailNum((SnailNumInner, SnailNumInner));
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Explode(l, r) => write!(f, "explode [{},{}]", l, r),
+            Step::Split(val) => write!(f, "split {}", val),
+        }
+    }
+}
 
-fn propagate_val_left(s: &mut SnailNum, val: i64) -> bool {
-    match &mut s.0 .1 {
-        SnailNumInner::Number(n) => {
-            *n += val;
-            return true;
+impl SnailNum {
+    /// Explode the leftmost pair of regular numbers nested inside four others
+    fn explode(&mut self) -> Option<Step> {
+        // Neighbours at the same depth are only siblings if no pair of regular
+        // numbers sits further left, so the first such neighbours deeper than
+        // four are the pair, even in parsed numbers nested deeper than five
+        let i = self
+            .0
+            .windows(2)
+            .position(|w| w[0].1 > 4 && w[0].1 == w[1].1)?;
+        let (l, depth) = self.0[i];
+        let (r, _) = self.0[i + 1];
+        if i > 0 {
+            self.0[i - 1].0 += l;
         }
-        SnailNumInner::SnailNum(sn) => {
-            let propagated = propagate_val_left(sn, val);
-            if propagated {
-                return propagated;
-            }
+        if let Some(next) = self.0.get_mut(i + 2) {
+            next.0 += r;
         }
+        self.0[i] = (0, depth - 1);
+        self.0.remove(i + 1);
+        Some(Step::Explode(l, r))
+    }
+
+    /// Split the leftmost number that's 10 or greater
+    fn split(&mut self) -> Option<Step> {
+        let i = self.0.iter().position(|&(val, _)| val >= 10)?;
+        let (val, depth) = self.0[i];
+        self.0[i] = (val / 2, depth + 1);
+        self.0.insert(i + 1, ((val + 1) / 2, depth + 1));
+        Some(Step::Split(val))
+    }
+
+    /// Apply one reduction action, None once fully reduced
+    fn step(&mut self) -> Option<Step> {
+        self.explode().or_else(|| self.split())
+    }
+
+    fn reduce(&mut self) {
+        while self.step().is_some() {}
     }
-    match &mut s.0 .0 {
-        SnailNumInner::Number(n) => {
-            *n += val;
-            return true;
+
+    /// Like `reduce`, but prints every action and the number it leaves behind
+    fn reduce_traced(&mut self) {
+        while let Some(step) = self.step() {
+            eprintln!("{:<20}{}", step.to_string(), self);
         }
-        SnailNumInner::SnailNum(sn) => {
-            return propagate_val_left(sn, val);
+    }
+
+    /// Pair up without reducing
+    fn concat(&self, rhs: &SnailNum) -> SnailNum {
+        SnailNum(
+            self.0
+                .iter()
+                .chain(rhs.0.iter())
+                .map(|&(val, depth)| (val, depth + 1))
+                .collect(),
+        )
+    }
+
+    fn add_traced(&self, rhs: &SnailNum) -> SnailNum {
+        let mut res = self.concat(rhs);
+        eprintln!("{:<20}{}", "after addition", res);
+        res.reduce_traced();
+        res
+    }
+
+    fn magnitude(&self) -> i64 {
+        // Collapse sibling pairs as soon as both halves are on the stack. Only
+        // finished subtrees sit below the top, so equal depths mean siblings.
+        let mut stack = Vec::<(i64, u8)>::with_capacity(self.0.len());
+        for &elem in self.0.iter() {
+            stack.push(elem);
+            while stack.len() >= 2 && stack[stack.len() - 1].1 == stack[stack.len() - 2].1 {
+                let (r, depth) = stack.pop().unwrap();
+                let (l, _) = stack.pop().unwrap();
+                stack.push((3 * l + 2 * r, depth - 1));
+            }
         }
+        stack[0].0
+    }
+
+    /// Write the element at `*i`, which sits somewhere below `depth`
+    fn fmt_at(&self, f: &mut fmt::Formatter, i: &mut usize, depth: u8) -> fmt::Result {
+        let (val, val_depth) = self.0[*i];
+        if val_depth == depth {
+            *i += 1;
+            write!(f, "{}", val)
+        } else {
+            write!(f, "[")?;
+            self.fmt_at(f, i, depth + 1)?;
+            write!(f, ",")?;
+            self.fmt_at(f, i, depth + 1)?;
+            write!(f, "]")
+        }
+    }
+}
+
+impl fmt::Display for SnailNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_at(f, &mut 0, 0)
     }
 }
 
-fn propagate_val_right(s: &mut SnailNum, val: i64) -> bool {
-    match &mut s.0 .0 {
-        SnailNumInner::Number(n) => {
-            *n += val;
-            return true;
+impl<'a> Add<&'a SnailNum> for &'a SnailNum {
+    type Output = SnailNum;
+    fn add(self, rhs: &'a SnailNum) -> SnailNum {
+        let mut res = self.concat(rhs);
+        res.reduce();
+        res
+    }
+}
+
+impl Add for SnailNum {
+    type Output = SnailNum;
+    fn add(self, rhs: SnailNum) -> SnailNum {
+        &self + &rhs
+    }
+}
+
+/// Snailfish addition has no identity, so summing nothing panics
+impl<'a> Sum<&'a SnailNum> for SnailNum {
+    fn sum<I: Iterator<Item = &'a SnailNum>>(mut iter: I) -> SnailNum {
+        let first = iter.next().expect("can't sum zero snailfish numbers");
+        iter.fold(first.clone(), |acc, x| &acc + x)
+    }
+}
+
+impl Sum for SnailNum {
+    fn sum<I: Iterator<Item = SnailNum>>(mut iter: I) -> SnailNum {
+        let first = iter.next().expect("can't sum zero snailfish numbers");
+        iter.fold(first, |acc, x| acc + x)
+    }
+}
+
+/// Recursive descent over the bracket notation
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn expect(&mut self, want: u8) -> Result<(), Box<dyn Error>> {
+        match self.s.get(self.pos) {
+            Some(&c) if c == want => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(&c) => Err(format!(
+                "expected '{}' at {}, found '{}'",
+                want as char, self.pos, c as char
+            ))?,
+            None => Err(format!(
+                "expected '{}' at {}, found the end",
+                want as char, self.pos
+            ))?,
         }
-        SnailNumInner::SnailNum(sn) => {
-            let propagated = propagate_val_right(sn, val);
-            if propagated {
-                return propagated;
+    }
+
+    fn elem(&mut self, depth: u8, elems: &mut Vec<(i64, u8)>) -> Result<(), Box<dyn Error>> {
+        if self.s.get(self.pos) == Some(&b'[') {
+            if depth == u8::MAX {
+                Err(format!("nested too deeply at {}", self.pos))?
             }
+            self.expect(b'[')?;
+            self.elem(depth + 1, elems)?;
+            self.expect(b',')?;
+            self.elem(depth + 1, elems)?;
+            self.expect(b']')
+        } else {
+            let start = self.pos;
+            while self.s.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                self.pos += 1;
+            }
+            if start == self.pos {
+                Err(format!("expected '[' or a digit at {}", start))?
+            }
+            let val = std::str::from_utf8(&self.s[start..self.pos])?.parse::<i64>()?;
+            elems.push((val, depth));
+            Ok(())
         }
     }
-    match &mut s.0 .1 {
-        SnailNumInner::Number(n) => {
-            *n += val;
-            return true;
+}
+
+impl FromStr for SnailNum {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.starts_with('[') {
+            Err("a snailfish number has to be a pair")?
         }
-        SnailNumInner::SnailNum(sn) => {
-            return propagate_val_right(sn, val);
+
+        let mut parser = Parser {
+            s: s.as_bytes(),
+            pos: 0,
+        };
+        let mut elems = Vec::new();
+        parser.elem(0, &mut elems)?;
+        if parser.pos != s.len() {
+            Err(format!("trailing characters at {}", parser.pos))?
         }
+
+        Ok(SnailNum(elems))
     }
 }
 
-fn explode_recurse(sn: &mut SnailNum, count: usize) -> (bool, bool, Option<i64>, Option<i64>) {
-    if count >= 4 {
-        let left = match sn.0 .0 {
-            SnailNumInner::Number(num) => num,
-            _ => panic!("should be a num"),
-        };
-        let right = match sn.0 .1 {
-            SnailNumInner::Number(num) => num,
-            _ => panic!("should be a num"),
-        };
-        return (true, true, Some(left), Some(right));
-    } else {
-        match &mut sn.0 .0 {
+fn parse_all(s: &str) -> Vec<SnailNum> {
+    s.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.parse().unwrap())
+        .collect()
+}
+
+fn p1(nums: &[SnailNum]) -> i64 {
+    nums.iter().sum::<SnailNum>().magnitude()
+}
+
+fn p2(nums: &[SnailNum]) -> i64 {
+    let mut mag_max = i64::MIN;
+
+    for (i, i_num) in nums.iter().enumerate() {
+        for (j, j_num) in nums.iter().enumerate() {
+            if i != j {
+                mag_max = mag_max.max((i_num + j_num).magnitude());
+            }
+        }
+    }
+
+    mag_max
+}
+
+/// The original boxed tree representation, kept as a reference for the flat one
+#[cfg(test)]
+mod tree {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum SnailNumInner {
+        SnailNum(Box<SnailNum>),
+        Number(i64),
+    }
+
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub struct SnailNum(pub (SnailNumInner, SnailNumInner));
+
+    fn propagate_val_left(s: &mut SnailNum, val: i64) -> bool {
+        match &mut s.0 .1 {
+            SnailNumInner::Number(n) => {
+                *n += val;
+                return true;
+            }
+            SnailNumInner::SnailNum(sn) => {
+                let propagated = propagate_val_left(sn, val);
+                if propagated {
+                    return propagated;
+                }
+            }
+        }
+        match &mut s.0 .0 {
+            SnailNumInner::Number(n) => {
+                *n += val;
+                true
+            }
+            SnailNumInner::SnailNum(sn) => propagate_val_left(sn, val),
+        }
+    }
+
+    fn propagate_val_right(s: &mut SnailNum, val: i64) -> bool {
+        match &mut s.0 .0 {
+            SnailNumInner::Number(n) => {
+                *n += val;
+                return true;
+            }
+            SnailNumInner::SnailNum(sn) => {
+                let propagated = propagate_val_right(sn, val);
+                if propagated {
+                    return propagated;
+                }
+            }
+        }
+        match &mut s.0 .1 {
+            SnailNumInner::Number(n) => {
+                *n += val;
+                true
+            }
+            SnailNumInner::SnailNum(sn) => propagate_val_right(sn, val),
+        }
+    }
+
+    pub fn explode_recurse(
+        sn: &mut SnailNum,
+        count: usize,
+    ) -> (bool, bool, Option<i64>, Option<i64>) {
+        if count >= 4 {
+            let left = match sn.0 .0 {
+                SnailNumInner::Number(num) => num,
+                _ => panic!("should be a num"),
+            };
+            let right = match sn.0 .1 {
+                SnailNumInner::Number(num) => num,
+                _ => panic!("should be a num"),
+            };
+            return (true, true, Some(left), Some(right));
+        } else {
             // first .0 is to get passed the newtype, second is for the left pair
-            SnailNumInner::SnailNum(sn_inner) => {
+            if let SnailNumInner::SnailNum(sn_inner) = &mut sn.0 .0 {
                 let (anything_changed, becomes_zero, new_l, mut new_r) =
                     explode_recurse(&mut *sn_inner, count + 1);
                 if becomes_zero {
@@ -96,10 +348,7 @@ fn explode_recurse(sn: &mut SnailNum, count: usize) -> (bool, bool, Option<i64>,
                     return (true, false, new_l, new_r);
                 }
             }
-            _ => {}
-        }
-        match &mut sn.0 .1 {
-            SnailNumInner::SnailNum(sn_inner) => {
+            if let SnailNumInner::SnailNum(sn_inner) = &mut sn.0 .1 {
                 let (anything_changed, becomes_zero, mut new_l, new_r) =
                     explode_recurse(&mut *sn_inner, count + 1);
                 if becomes_zero {
@@ -122,174 +371,179 @@ fn explode_recurse(sn: &mut SnailNum, count: usize) -> (bool, bool, Option<i64>,
                     return (true, false, new_l, new_r);
                 }
             }
-            _ => {}
         }
-    }
 
-    return (false, false, None, None);
-}
+        (false, false, None, None)
+    }
 
-fn explode_til_completion(sn: &SnailNum) -> SnailNum {
-    let mut res = sn.clone();
-    while explode_recurse(&mut res, 0).0 {}
-    res
-}
+    fn explode_til_completion(sn: &SnailNum) -> SnailNum {
+        let mut res = sn.clone();
+        while explode_recurse(&mut res, 0).0 {}
+        res
+    }
 
-fn split_once(sn: &mut SnailNum) -> bool {
-    match &mut sn.0 .0 {
-        SnailNumInner::Number(num) => {
-            if *num >= 10 {
-                let l = *num / 2;
-                let r = (*num + 1) / 2;
+    fn split_once(sn: &mut SnailNum) -> bool {
+        match &mut sn.0 .0 {
+            SnailNumInner::Number(num) => {
+                if *num >= 10 {
+                    let l = *num / 2;
+                    let r = (*num + 1) / 2;
 
-                sn.0 .0 = SnailNumInner::SnailNum(Box::new(SnailNum((
-                    SnailNumInner::Number(l),
-                    SnailNumInner::Number(r),
-                ))));
+                    sn.0 .0 = SnailNumInner::SnailNum(Box::new(SnailNum((
+                        SnailNumInner::Number(l),
+                        SnailNumInner::Number(r),
+                    ))));
 
-                return true;
+                    return true;
+                }
             }
-        }
-        SnailNumInner::SnailNum(sn_inner) => {
-            if split_once(sn_inner) {
-                return true;
+            SnailNumInner::SnailNum(sn_inner) => {
+                if split_once(sn_inner) {
+                    return true;
+                }
             }
-        }
-    };
-    match &mut sn.0 .1 {
-        SnailNumInner::Number(num) => {
-            if *num >= 10 {
-                let l = *num / 2;
-                let r = (*num + 1) / 2;
-
-                sn.0 .1 = SnailNumInner::SnailNum(Box::new(SnailNum((
-                    SnailNumInner::Number(l),
-                    SnailNumInner::Number(r),
-                ))));
+        };
+        match &mut sn.0 .1 {
+            SnailNumInner::Number(num) => {
+                if *num >= 10 {
+                    let l = *num / 2;
+                    let r = (*num + 1) / 2;
 
-                return true;
+                    sn.0 .1 = SnailNumInner::SnailNum(Box::new(SnailNum((
+                        SnailNumInner::Number(l),
+                        SnailNumInner::Number(r),
+                    ))));
+
+                    return true;
+                }
             }
-        }
-        SnailNumInner::SnailNum(sn_inner) => {
-            return split_once(sn_inner);
-        }
-    };
+            SnailNumInner::SnailNum(sn_inner) => {
+                return split_once(sn_inner);
+            }
+        };
 
-    return false;
-}
+        false
+    }
+
+    fn reduce(sn: &SnailNum) -> SnailNum {
+        let mut res = sn.clone();
+        let mut last = None;
+        while last.is_none() || last.unwrap() != res {
+            last = Some(res.clone());
+            res = explode_til_completion(&res);
+            split_once(&mut res);
+        }
 
-fn reduce(sn: &SnailNum) -> SnailNum {
-    let mut res = sn.clone();
-    let mut last = None;
-    while last.is_none() || last.unwrap() != res {
-        last = Some(res.clone());
-        res = explode_til_completion(&res);
-        split_once(&mut res);
+        res
     }
 
-    res
-}
+    pub fn add(lhs: &SnailNum, rhs: &SnailNum) -> SnailNum {
+        let added = SnailNum((
+            SnailNumInner::SnailNum(Box::new(lhs.clone())),
+            SnailNumInner::SnailNum(Box::new(rhs.clone())),
+        ));
 
-fn add(lhs: &SnailNum, rhs: &SnailNum) -> SnailNum {
-    let added = SnailNum((
-        SnailNumInner::SnailNum(Box::new(lhs.clone())),
-        SnailNumInner::SnailNum(Box::new(rhs.clone())),
-    ));
+        reduce(&added)
+    }
 
-    reduce(&added)
-}
+    pub fn parse_all(s: &str) -> Vec<SnailNum> {
+        s.lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| parse(l).1)
+            .collect()
+    }
 
-fn parse_all(s: &str) -> Vec<SnailNum> {
-    s.lines()
-        .filter(|l| !l.is_empty())
-        .map(|l| parse(l).1)
-        .collect()
-}
+    pub fn parse(s: &str) -> (usize, SnailNum) {
+        assert!(s.is_ascii());
+        assert_eq!(&s[0..1], "[");
+        let mut reslice = &s[1..];
+        let mut idx = 1;
+
+        // First
+        let first_inner = if &reslice[0..1] == "[" {
+            let (step, num) = parse(reslice);
+            reslice = &reslice[step + 1..];
+            idx += step + 1;
+            SnailNumInner::SnailNum(Box::new(num))
+        } else {
+            let comma_idx = &reslice.find(',').unwrap();
+            let num = &reslice[0..*comma_idx].parse::<i64>().unwrap();
+            reslice = &reslice[comma_idx + 1..];
+            idx += comma_idx + 1;
+            SnailNumInner::Number(*num)
+        };
 
-fn parse(s: &str) -> (usize, SnailNum) {
-    assert!(s.is_ascii());
-    assert_eq!(&s[0..1], "[");
-    let mut reslice = &s[1..];
-    let mut idx = 1;
-
-    // First
-    let first_inner = if &reslice[0..1] == "[" {
-        let (step, num) = parse(&reslice);
-        reslice = &reslice[step + 1..];
-        idx += step + 1;
-        SnailNumInner::SnailNum(Box::new(num))
-    } else {
-        let comma_idx = &reslice.find(',').unwrap();
-        let num = &reslice[0..*comma_idx].parse::<i64>().unwrap();
-        reslice = &reslice[comma_idx + 1..];
-        idx += comma_idx + 1;
-        SnailNumInner::Number(*num)
-    };
-
-    // Second
-    let second_inner = if &reslice[0..1] == "[" {
-        let (step, num) = parse(&reslice);
-        //reslice = &reslice[step + 1..];
-        idx += step + 1;
-        SnailNumInner::SnailNum(Box::new(num))
-    } else {
-        let bracket_idx = &reslice.find(']').unwrap();
-        let num = &reslice[0..*bracket_idx].parse::<i64>().unwrap();
-        //reslice = &reslice[bracket_idx + 1..];
-        idx += bracket_idx + 1;
-        SnailNumInner::Number(*num)
-    };
-
-    (idx, SnailNum((first_inner, second_inner)))
-}
+        // Second
+        let second_inner = if &reslice[0..1] == "[" {
+            let (step, num) = parse(reslice);
+            //reslice = &reslice[step + 1..];
+            idx += step + 1;
+            SnailNumInner::SnailNum(Box::new(num))
+        } else {
+            let bracket_idx = &reslice.find(']').unwrap();
+            let num = &reslice[0..*bracket_idx].parse::<i64>().unwrap();
+            //reslice = &reslice[bracket_idx + 1..];
+            idx += bracket_idx + 1;
+            SnailNumInner::Number(*num)
+        };
 
-fn mag_recurse(s: &SnailNum) -> i64 {
-    let left = match &s.0 .0 {
-        SnailNumInner::Number(n) => *n,
-        SnailNumInner::SnailNum(sn) => mag_recurse(&sn),
-    };
-    let right = match &s.0 .1 {
-        SnailNumInner::Number(n) => *n,
-        SnailNumInner::SnailNum(sn) => mag_recurse(&sn),
-    };
-    3 * left + 2 * right
-}
+        (idx, SnailNum((first_inner, second_inner)))
+    }
 
-fn p1(nums: &[SnailNum]) -> i64 {
-    let sum = nums
-        .iter()
-        .skip(1)
-        .fold(nums[0].clone(), |acc, x| add(&acc, x));
-    mag_recurse(&sum)
-}
+    pub fn mag_recurse(s: &SnailNum) -> i64 {
+        let left = match &s.0 .0 {
+            SnailNumInner::Number(n) => *n,
+            SnailNumInner::SnailNum(sn) => mag_recurse(sn),
+        };
+        let right = match &s.0 .1 {
+            SnailNumInner::Number(n) => *n,
+            SnailNumInner::SnailNum(sn) => mag_recurse(sn),
+        };
+        3 * left + 2 * right
+    }
 
-fn p2(nums: &[SnailNum]) -> i64 {
-    let mut mag_max = i64::MIN;
+    pub fn p1(nums: &[SnailNum]) -> i64 {
+        let sum = nums
+            .iter()
+            .skip(1)
+            .fold(nums[0].clone(), |acc, x| add(&acc, x));
+        mag_recurse(&sum)
+    }
 
-    for i in 0..nums.len() {
-        let i_num = &nums[i];
-        for j in i + 1..nums.len() {
-            let j_num = &nums[j];
-            let ij_arr = [i_num.clone(), j_num.clone()];
-            let ij_mag = p1(&ij_arr[..]);
-            let ji_arr = [j_num.clone(), i_num.clone()];
-            let ji_mag = p1(&ji_arr);
-            mag_max = mag_max.max(ij_mag);
-            mag_max = mag_max.max(ji_mag);
+    pub fn p2(nums: &[SnailNum]) -> i64 {
+        let mut mag_max = i64::MIN;
+
+        for (i, i_num) in nums.iter().enumerate() {
+            for j_num in nums.iter().skip(i + 1) {
+                let ij_arr = [i_num.clone(), j_num.clone()];
+                let ij_mag = p1(&ij_arr[..]);
+                let ji_arr = [j_num.clone(), i_num.clone()];
+                let ji_mag = p1(&ji_arr);
+                mag_max = mag_max.max(ij_mag);
+                mag_max = mag_max.max(ji_mag);
+            }
         }
-    }
 
-    mag_max
+        mag_max
+    }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    // Pass --trace to print every reduction step of the part 1 sum
+    let trace = args.len() == 3 && args[2] == "--trace";
+    if args.len() != 2 && !trace {
+        panic!("There should be one argument, optionally followed by --trace");
     }
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
     let nums = parse_all(&file_str);
+    if trace {
+        let sum = nums[1..]
+            .iter()
+            .fold(nums[0].clone(), |acc, x| acc.add_traced(x));
+        eprintln!("{:<20}{}", "sum", sum);
+    }
     let p1_res = p1(&nums);
     println!("P1: {}", p1_res);
     let p2_res = p2(&nums);
@@ -299,15 +553,16 @@ fn main() {
 #[cfg(test)]
 mod test_day18 {
     use super::*;
+    use std::time::Instant;
 
     #[test]
     fn test() {
         {
-            let ex = parse("[[[[[9,8],1],2],3],4]").1;
-            let answer = parse("[[[[0,9],2],3],4]").1;
+            let ex = tree::parse("[[[[[9,8],1],2],3],4]").1;
+            let answer = tree::parse("[[[[0,9],2],3],4]").1;
 
             let mut res = ex.clone();
-            let (changed, zero, l, r) = explode_recurse(&mut res, 0);
+            let (changed, zero, l, r) = tree::explode_recurse(&mut res, 0);
             assert!(changed);
             assert!(!zero);
             assert!(l.is_some());
@@ -315,11 +570,11 @@ mod test_day18 {
             assert_eq!(res, answer);
         }
         {
-            let ex = parse("[7,[6,[5,[4,[3,2]]]]]").1;
-            let answer = parse("[7,[6,[5,[7,0]]]]").1;
+            let ex = tree::parse("[7,[6,[5,[4,[3,2]]]]]").1;
+            let answer = tree::parse("[7,[6,[5,[7,0]]]]").1;
 
             let mut res = ex.clone();
-            let (changed, zero, l, r) = explode_recurse(&mut res, 0);
+            let (changed, zero, l, r) = tree::explode_recurse(&mut res, 0);
             assert!(changed);
             assert!(!zero);
             assert!(l.is_none());
@@ -327,11 +582,11 @@ mod test_day18 {
             assert_eq!(res, answer);
         }
         {
-            let ex = parse("[[3,[2,[1,[7,3]]]],[6,[5,[4,[3,2]]]]]").1;
-            let answer = parse("[[3,[2,[8,0]]],[9,[5,[4,[3,2]]]]]").1;
+            let ex = tree::parse("[[3,[2,[1,[7,3]]]],[6,[5,[4,[3,2]]]]]").1;
+            let answer = tree::parse("[[3,[2,[8,0]]],[9,[5,[4,[3,2]]]]]").1;
 
             let mut res = ex.clone();
-            let (changed, zero, l, r) = explode_recurse(&mut res, 0);
+            let (changed, zero, l, r) = tree::explode_recurse(&mut res, 0);
             assert!(changed);
             assert!(!zero);
             assert!(l.is_none());
@@ -339,12 +594,121 @@ mod test_day18 {
             assert_eq!(res, answer);
         }
         {
-            let lhs = parse("[[[[4,3],4],4],[7,[[8,4],9]]]").1;
-            let rhs = parse("[1,1]").1;
-            let answer = parse("[[[[0,7],4],[[7,8],[6,0]]],[8,1]]").1;
+            let lhs = tree::parse("[[[[4,3],4],4],[7,[[8,4],9]]]").1;
+            let rhs = tree::parse("[1,1]").1;
+            let answer = tree::parse("[[[[0,7],4],[[7,8],[6,0]]],[8,1]]").1;
 
-            let added = add(&lhs, &rhs);
+            let added = tree::add(&lhs, &rhs);
             assert_eq!(added, answer);
         }
     }
+
+    fn sn(s: &str) -> SnailNum {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_flat() {
+        {
+            let mut res = sn("[[[[[9,8],1],2],3],4]");
+            assert!(res.explode().is_some());
+            assert_eq!(res, sn("[[[[0,9],2],3],4]"));
+        }
+        {
+            let mut res = sn("[[3,[2,[1,[7,3]]]],[6,[5,[4,[3,2]]]]]");
+            assert!(res.explode().is_some());
+            assert_eq!(res, sn("[[3,[2,[8,0]]],[9,[5,[4,[3,2]]]]]"));
+        }
+        {
+            // 1 and 2 are neighbours deeper than four, but only [2,3] is a pair
+            let mut res = sn("[[[[[1,[2,3]],4],5],6],7]");
+            assert_eq!(res.explode(), Some(Step::Explode(2, 3)));
+            assert_eq!(res, sn("[[[[[3,0],7],5],6],7]"));
+            assert_eq!(res.explode(), Some(Step::Explode(3, 0)));
+            assert_eq!(res, sn("[[[[0,7],5],6],7]"));
+        }
+        {
+            let lhs = sn("[[[[4,3],4],4],[7,[[8,4],9]]]");
+            let rhs = sn("[1,1]");
+            let answer = sn("[[[[0,7],4],[[7,8],[6,0]]],[8,1]]");
+            assert_eq!(&lhs + &rhs, answer);
+        }
+        assert_eq!(sn("[[1,2],[[3,4],5]]").magnitude(), 143);
+        assert_eq!(
+            sn("[[[[8,7],[7,7]],[[8,6],[7,7]]],[[[0,7],[6,6]],[8,7]]]").magnitude(),
+            3488
+        );
+
+        let example = r#"[[[0,[5,8]],[[1,7],[9,6]]],[[4,[1,2]],[[1,4],2]]]
+[[[5,[2,8]],4],[5,[[9,9],0]]]
+[6,[[[6,2],[5,6]],[[7,6],[4,7]]]]
+[[[6,[0,7]],[0,9]],[4,[9,[9,0]]]]
+[[[7,[6,4]],[3,[1,3]]],[[[5,5],1],9]]
+[[6,[[7,3],[3,2]]],[[[3,8],[5,7]],4]]
+[[[[5,4],[7,7]],8],[[8,3],8]]
+[[9,3],[[9,9],[6,[4,9]]]]
+[[2,[[7,7],7]],[[5,8],[[9,3],[0,2]]]]
+[[[[5,2],5],[8,[3,7]]],[[5,[7,5]],[4,4]]]"#;
+        let nums = parse_all(example);
+        let tree_nums = tree::parse_all(example);
+        assert_eq!(p1(&nums), 4140);
+        assert_eq!(p1(&nums), tree::p1(&tree_nums));
+        assert_eq!(p2(&nums), 3993);
+        assert_eq!(p2(&nums), tree::p2(&tree_nums));
+    }
+
+    #[test]
+    fn test_traits() {
+        for s in [
+            "[1,2]",
+            "[[1,9],[8,5]]",
+            "[[[[1,3],[5,3]],[[1,3],[8,7]]],[[[4,9],[6,9]],[[8,2],[7,3]]]]",
+        ] {
+            assert_eq!(sn(s).to_string(), s);
+        }
+        assert!("".parse::<SnailNum>().is_err());
+        assert!("7".parse::<SnailNum>().is_err());
+        assert!("[1,2".parse::<SnailNum>().is_err());
+        assert!("[1;2]".parse::<SnailNum>().is_err());
+        assert!("[[1,2],]".parse::<SnailNum>().is_err());
+        assert!("[1,2]]".parse::<SnailNum>().is_err());
+
+        let mut res = sn("[[[[0,7],4],[15,[0,13]]],[1,1]]");
+        assert_eq!(res.step(), Some(Step::Split(15)));
+        assert_eq!(res.step(), Some(Step::Split(13)));
+        assert_eq!(res.step(), Some(Step::Explode(6, 7)));
+        assert_eq!(res.step(), None);
+        assert_eq!(res, sn("[[[[0,7],4],[[7,8],[6,0]]],[8,1]]"));
+
+        let nums = parse_all("[1,1]\n[2,2]\n[3,3]\n[4,4]\n[5,5]\n[6,6]");
+        assert_eq!(
+            nums.iter().sum::<SnailNum>(),
+            sn("[[[[5,0],[7,4]],[5,5]],[6,6]]")
+        );
+        assert_eq!(
+            nums.into_iter().sum::<SnailNum>().to_string(),
+            "[[[[5,0],[7,4]],[5,5]],[6,6]]"
+        );
+    }
+
+    /// Compare the flat and tree representations on the real input with
+    /// `cargo test --release --bin aoc_18 -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_flat_vs_tree() {
+        let file_str = std::fs::read_to_string("inputs/input_18").unwrap();
+
+        let start = Instant::now();
+        let nums = parse_all(&file_str);
+        let flat = (p1(&nums), p2(&nums));
+        let flat_time = start.elapsed();
+
+        let start = Instant::now();
+        let tree_nums = tree::parse_all(&file_str);
+        let tree = (tree::p1(&tree_nums), tree::p2(&tree_nums));
+        let tree_time = start.elapsed();
+
+        assert_eq!(flat, tree);
+        eprintln!("flat: {:?}, tree: {:?}", flat_time, tree_time);
+    }
 }
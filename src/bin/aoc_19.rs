@@ -3,12 +3,22 @@
 use na::Point3;
 use na::Vector3;
 use nalgebra as na;
-use petgraph::graphmap::UnGraphMap;
-use std::env;
+use pose_graph::PoseGraph;
+use rng::XorShift64;
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
 
 #[macro_use]
 extern crate lazy_static;
 
+#[path = "common/rng.rs"]
+mod rng;
+
 lazy_static! {
     static ref DIR_PERMS: Vec<na::Matrix3<i64>> = perms();
 }
@@ -74,124 +84,531 @@ fn parse(s: &str) -> Vec<Vec<Point3<i64>>> {
         .collect::<Vec<_>>()
 }
 
+/// How closely two scanners have to agree before they're aligned
+#[derive(Debug, Clone, Copy)]
+struct AlignmentConfig {
+    /// Beacons two scanners must share to count as overlapping
+    min_overlap: usize,
+    /// Beacons within this distance of each other count as the same one
+    tolerance: i64,
+    /// Search for the transform with the most inliers instead of taking the
+    /// first one that's good enough
+    ransac: Option<RansacConfig>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RansacConfig {
+    iterations: usize,
+    seed: u64,
+}
+
+impl Default for AlignmentConfig {
+    fn default() -> Self {
+        Self {
+            min_overlap: 12,
+            tolerance: 0,
+            ransac: None,
+        }
+    }
+}
+
+impl AlignmentConfig {
+    /// How far apart the distances between two beacons can be when measured
+    /// by different scanners, since each end can be off by `tolerance`
+    fn dist_slack(&self) -> f64 {
+        2.0 * self.tolerance as f64
+    }
+}
+
+fn within(a: &Vector3<i64>, b: &Vector3<i64>, tolerance: i64) -> bool {
+    let diff = a - b;
+    diff.dot(&diff) <= tolerance * tolerance
+}
+
+/// Spatial hash answering "is there a beacon within tolerance of this point".
+/// With zero tolerance it's a plain hash lookup.
+struct BeaconIndex {
+    tolerance: i64,
+    cells: HashMap<Point3<i64>, Vec<Point3<i64>>>,
+}
+
+impl BeaconIndex {
+    fn new(tolerance: i64) -> Self {
+        Self {
+            tolerance,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn from_points(pts: &[Point3<i64>], tolerance: i64) -> Self {
+        let mut index = Self::new(tolerance);
+        for pt in pts.iter() {
+            index.insert(*pt);
+        }
+        index
+    }
+
+    fn cell(&self, pt: &Point3<i64>) -> Point3<i64> {
+        pt.map(|c| c.div_euclid(self.tolerance.max(1)))
+    }
+
+    fn insert(&mut self, pt: Point3<i64>) {
+        self.cells.entry(self.cell(&pt)).or_default().push(pt);
+    }
+
+    fn find(&self, pt: &Point3<i64>) -> Option<&Point3<i64>> {
+        let cell = self.cell(pt);
+        let reach = if self.tolerance == 0 { 0 } else { 1 };
+        for dx in -reach..=reach {
+            for dy in -reach..=reach {
+                for dz in -reach..=reach {
+                    let neighbour = cell + Vector3::new(dx, dy, dz);
+                    let found = self.cells.get(&neighbour).and_then(|pts| {
+                        pts.iter()
+                            .find(|other| within(&other.coords, &pt.coords, self.tolerance))
+                    });
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Rotation and translation invariant summary of a scanner: the distance
+/// between every pair of its beacons, sorted by distance
+struct Fingerprint(Vec<(f64, usize, usize)>);
+
+impl Fingerprint {
+    fn new(pts: &[Point3<i64>]) -> Self {
+        let mut dists = Vec::new();
+        for (a, pt_a) in pts.iter().enumerate() {
+            for (b, pt_b) in pts.iter().enumerate().skip(a + 1) {
+                let diff = pt_a - pt_b;
+                dists.push(((diff.dot(&diff) as f64).sqrt(), a, b));
+            }
+        }
+        dists.sort_by(|l, r| l.0.partial_cmp(&r.0).unwrap());
+        Fingerprint(dists)
+    }
+
+    /// Beacon pairs whose distance is within `slack` of `dist`
+    fn near(&self, dist: f64, slack: f64) -> &[(f64, usize, usize)] {
+        let lo = self.0.partition_point(|e| e.0 < dist - slack);
+        let hi = self.0.partition_point(|e| e.0 <= dist + slack);
+        &self.0[lo..hi]
+    }
+
+    /// How many of our beacon pairs have a counterpart in `other`
+    fn shared(&self, other: &Fingerprint, slack: f64) -> usize {
+        self.0
+            .iter()
+            .filter(|e| !other.near(e.0, slack).is_empty())
+            .count()
+    }
+
+    /// Whether the scanners share enough distances to possibly overlap. N
+    /// common beacons imply at least N choose 2 common distances.
+    fn may_overlap(&self, other: &Fingerprint, config: &AlignmentConfig) -> bool {
+        let n = config.min_overlap;
+        self.shared(other, config.dist_slack()) >= n * n.saturating_sub(1) / 2
+    }
+}
+
+/// Every transform that lines up beacon pair (a2, b2) of p2 with (a1, b1) of p1
+fn hypotheses(
+    p1: &[Point3<i64>],
+    (a1, b1): (usize, usize),
+    p2: &[Point3<i64>],
+    (a2, b2): (usize, usize),
+    tolerance: i64,
+) -> Vec<(na::Matrix3<i64>, na::Vector3<i64>)> {
+    let span1 = p1[b1] - p1[a1];
+    let mid1 = p1[a1].coords + p1[b1].coords;
+    let mut res = vec![];
+    // The pair could line up either way round
+    for (from2, to2) in [(a2, b2), (b2, a2)] {
+        let span2 = p2[to2] - p2[from2];
+        for p1_from_p2 in DIR_PERMS.iter() {
+            if !within(&(p1_from_p2 * span2), &span1, 2 * tolerance) {
+                continue;
+            }
+            // Match up the midpoints, which halves the effect of noise
+            let mid2 = p2[from2].coords + p2[to2].coords;
+            res.push((*p1_from_p2, (mid1 - p1_from_p2 * mid2) / 2));
+        }
+    }
+    res
+}
+
+/// Number of p2's beacons that land on one of p1's
+fn count_inliers(
+    index: &BeaconIndex,
+    p2: &[Point3<i64>],
+    rot: &na::Matrix3<i64>,
+    trans: &na::Vector3<i64>,
+) -> usize {
+    p2.iter()
+        .filter(|pt| index.find(&(rot * *pt + trans)).is_some())
+        .count()
+}
+
+/// Find the transform taking p2's points into p1's frame. Only beacon pairs
+/// with matching fingerprint distances are tried as correspondences.
 fn attempt_pair_alignment(
     p1: &[Point3<i64>],
+    fp1: &Fingerprint,
     p2: &[Point3<i64>],
+    fp2: &Fingerprint,
+    config: &AlignmentConfig,
 ) -> Option<(na::Matrix3<i64>, na::Vector3<i64>)> {
-    for p1_from_p2 in DIR_PERMS.iter() {
-        for p1_pt in p1.iter() {
-            for p2_pt in p2.iter() {
-                let p2_pt_in_p1 = p1_from_p2 * p2_pt;
-                let translation = p1_pt - p2_pt_in_p1;
-
-                let mut count = 0;
-                let mut matches = vec![];
-                for p2_pt_eval in p2.iter() {
-                    let p2_pt_tranformed = p1_from_p2 * p2_pt_eval + translation;
-                    if p1.iter().find(|p| *p.coords == *p2_pt_tranformed).is_some() {
-                        matches.push(p2_pt_tranformed);
-                        count += 1;
+    let index = BeaconIndex::from_points(p1, config.tolerance);
+    if let Some(ransac) = &config.ransac {
+        return ransac_pair_alignment(&index, fp1, p1, fp2, p2, config, ransac);
+    }
+
+    for &(dist, a1, b1) in fp1.0.iter() {
+        for &(_, a2, b2) in fp2.near(dist, config.dist_slack()) {
+            for (rot, trans) in hypotheses(p1, (a1, b1), p2, (a2, b2), config.tolerance) {
+                if count_inliers(&index, p2, &rot, &trans) >= config.min_overlap {
+                    return Some((rot, trans));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Try randomly chosen correspondences and keep the transform with the most
+/// inliers, then refit its translation to all of them. Copes with beacons
+/// that only one scanner saw or that are in slightly the wrong place.
+fn ransac_pair_alignment(
+    index: &BeaconIndex,
+    fp1: &Fingerprint,
+    p1: &[Point3<i64>],
+    fp2: &Fingerprint,
+    p2: &[Point3<i64>],
+    config: &AlignmentConfig,
+    ransac: &RansacConfig,
+) -> Option<(na::Matrix3<i64>, na::Vector3<i64>)> {
+    if fp1.0.is_empty() {
+        return None;
+    }
+    let mut rng = XorShift64::new(ransac.seed);
+    let mut next = |bound: usize| rng.below(bound as u64) as usize;
+
+    let mut best: Option<(usize, na::Matrix3<i64>, na::Vector3<i64>)> = None;
+    for _ in 0..ransac.iterations {
+        let (dist, a1, b1) = fp1.0[next(fp1.0.len())];
+        let candidates = fp2.near(dist, config.dist_slack());
+        if candidates.is_empty() {
+            continue;
+        }
+        let (_, a2, b2) = candidates[next(candidates.len())];
+        for (rot, trans) in hypotheses(p1, (a1, b1), p2, (a2, b2), config.tolerance) {
+            let count = count_inliers(index, p2, &rot, &trans);
+            if best.is_none_or(|(best_count, _, _)| count > best_count) {
+                best = Some((count, rot, trans));
+            }
+        }
+    }
+
+    let (count, rot, trans) = best?;
+    if count < config.min_overlap {
+        return None;
+    }
+    let residuals = p2
+        .iter()
+        .filter_map(|pt| {
+            let moved = rot * pt + trans;
+            index.find(&moved).map(|matched| matched - moved)
+        })
+        .collect::<Vec<_>>();
+    let correction = residuals.iter().sum::<Vector3<i64>>() / residuals.len() as i64;
+    Some((rot, trans + correction))
+}
+
+/// Rigid transform taking a scanner's coordinates into scanner 0's frame. The
+/// translation is also where the scanner sits in that frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pose {
+    rot: na::Matrix3<i64>,
+    trans: na::Vector3<i64>,
+}
+
+impl Pose {
+    fn apply(&self, pt: &Point3<i64>) -> Point3<i64> {
+        self.rot * pt + self.trans
+    }
+}
+
+mod pose_graph {
+    use super::Pose;
+    use nalgebra as na;
+    use petgraph::graphmap::UnGraphMap;
+    use std::collections::VecDeque;
+
+    /// Pairwise alignments between scanners. An edge between i < j holds the
+    /// transform taking j's coordinates into i's.
+    #[derive(Default)]
+    pub struct PoseGraph {
+        graph: UnGraphMap<usize, (na::Matrix3<i64>, na::Vector3<i64>)>,
+    }
+
+    impl PoseGraph {
+        /// `rot` and `trans` take j's coordinates into i's
+        pub fn add_alignment(
+            &mut self,
+            i: usize,
+            j: usize,
+            rot: na::Matrix3<i64>,
+            trans: na::Vector3<i64>,
+        ) {
+            if i < j {
+                self.graph.add_edge(i, j, (rot, trans));
+            } else {
+                let inv_rot = rot.transpose();
+                self.graph.add_edge(j, i, (inv_rot, -inv_rot * trans));
+            }
+        }
+
+        /// Pose of every scanner in `root`'s frame, composed along a BFS
+        /// spanning tree. None for scanners with no path to `root`.
+        pub fn poses_from(&self, root: usize, count: usize) -> Vec<Option<Pose>> {
+            let mut poses = vec![None; count];
+            poses[root] = Some(Pose {
+                rot: na::Matrix3::<i64>::identity(),
+                trans: na::Vector3::<i64>::zeros(),
+            });
+            let mut queue = VecDeque::from(vec![root]);
+            while let Some(cur) = queue.pop_front() {
+                let cur_pose = poses[cur].unwrap();
+                for (_, next, &(mut rot, mut trans)) in self.graph.edges(cur) {
+                    if poses[next].is_some() {
+                        continue;
                     }
-                    if count >= 12 {
-                        return Some((p1_from_p2.clone(), translation));
+                    // Edge weights go from the higher index to the lower
+                    if next < cur {
+                        rot = rot.transpose();
+                        trans = -rot * trans;
                     }
+                    // compose, giving root_from_next
+                    poses[next] = Some(Pose {
+                        rot: cur_pose.rot * rot,
+                        trans: cur_pose.rot * trans + cur_pose.trans,
+                    });
+                    queue.push_back(next);
                 }
             }
+            poses
+        }
+    }
+}
+
+/// Everything recovered about the scanners, all in scanner 0's frame
+#[derive(Debug)]
+struct Reconstruction {
+    /// Indexed by scanner, None if it couldn't be aligned with scanner 0
+    poses: Vec<Option<Pose>>,
+    /// Deduplicated and sorted
+    beacons: Vec<Point3<i64>>,
+}
+
+impl Reconstruction {
+    /// Scanners that share no chain of overlaps with scanner 0
+    fn disconnected(&self) -> Vec<usize> {
+        (0..self.poses.len())
+            .filter(|&i| self.poses[i].is_none())
+            .collect()
+    }
+
+    fn aligned_poses(&self) -> impl Iterator<Item = &Pose> {
+        self.poses.iter().flatten()
+    }
+
+    fn max_scanner_distance(&self) -> i64 {
+        let mut max_manhatten = 0;
+        for (i, pose_i) in self.aligned_poses().enumerate() {
+            for pose_j in self.aligned_poses().skip(i + 1) {
+                let manhatten = (pose_i.trans - pose_j.trans).abs().sum();
+                max_manhatten = max_manhatten.max(manhatten);
+            }
+        }
+        max_manhatten
+    }
+
+    /// One row per beacon or scanner: `kind,x,y,z`
+    fn write_csv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "kind,x,y,z")?;
+        for pt in self.beacons.iter() {
+            writeln!(w, "beacon,{},{},{}", pt.x, pt.y, pt.z)?;
+        }
+        for pose in self.aligned_poses() {
+            let t = &pose.trans;
+            writeln!(w, "scanner,{},{},{}", t.x, t.y, t.z)?;
+        }
+        Ok(())
+    }
+
+    /// ASCII PLY with beacons in white and scanners in red
+    fn write_ply<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "ply")?;
+        writeln!(w, "format ascii 1.0")?;
+        writeln!(
+            w,
+            "element vertex {}",
+            self.beacons.len() + self.aligned_poses().count()
+        )?;
+        for prop in [
+            "int x",
+            "int y",
+            "int z",
+            "uchar red",
+            "uchar green",
+            "uchar blue",
+        ] {
+            writeln!(w, "property {}", prop)?;
+        }
+        writeln!(w, "end_header")?;
+        for pt in self.beacons.iter() {
+            writeln!(w, "{} {} {} 255 255 255", pt.x, pt.y, pt.z)?;
+        }
+        for pose in self.aligned_poses() {
+            let t = &pose.trans;
+            writeln!(w, "{} {} {} 255 0 0", t.x, t.y, t.z)?;
+        }
+        Ok(())
+    }
+
+    /// Wavefront OBJ vertices, with beacons and scanners as separate objects
+    fn write_obj<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "o beacons")?;
+        for pt in self.beacons.iter() {
+            writeln!(w, "v {} {} {}", pt.x, pt.y, pt.z)?;
+        }
+        writeln!(w, "o scanners")?;
+        for pose in self.aligned_poses() {
+            let t = &pose.trans;
+            writeln!(w, "v {} {} {}", t.x, t.y, t.z)?;
+        }
+        Ok(())
+    }
+
+    /// Pick the format from the file extension
+    fn export(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => self.write_csv(&mut w),
+            Some("ply") => self.write_ply(&mut w),
+            Some("obj") => self.write_obj(&mut w),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "export path should end in .csv, .ply or .obj",
+            )),
         }
     }
-    None
 }
 
-fn p1_p2(scanners: &Vec<Vec<Point3<i64>>>) {
+fn reconstruct(scanners: &[Vec<Point3<i64>>], config: &AlignmentConfig) -> Reconstruction {
+    let fingerprints = scanners
+        .iter()
+        .map(|s| Fingerprint::new(s))
+        .collect::<Vec<_>>();
     let mut mappings = Vec::<(usize, usize, na::Matrix3<i64>, na::Vector3<i64>)>::new();
     for (i, scan_i) in scanners.iter().enumerate() {
         for (j, scan_j) in scanners.iter().enumerate().skip(i + 1) {
+            if !fingerprints[i].may_overlap(&fingerprints[j], config) {
+                continue;
+            }
             // transform maps j to i, so tranform is valid if j > i
             // aka i_from_j
-            if let Some((r, t)) = attempt_pair_alignment(scan_i, scan_j) {
+            if let Some((r, t)) =
+                attempt_pair_alignment(scan_i, &fingerprints[i], scan_j, &fingerprints[j], config)
+            {
                 mappings.push((i, j, r, t));
             }
         }
     }
-    let mut graph = UnGraphMap::<usize, (na::Matrix3<i64>, na::Vector3<i64>)>::new();
+    let mut graph = PoseGraph::default();
     for (i, j, r, t) in mappings.iter().copied() {
-        graph.add_node(i);
-        graph.add_node(j);
-        graph.add_edge(i, j, (r, t));
-    }
-    let mut pts_in_zero_frame = std::collections::HashSet::<na::Point3<i64>>::new();
-    let max_scanner = scanners.len();
-    for i in 1..max_scanner {
-        let paths =
-            petgraph::algo::simple_paths::all_simple_paths::<Vec<_>, _>(&graph, i, 0, 0, None)
-                .collect::<Vec<_>>();
-        let path = &paths[0];
-        let mut rot = na::Matrix3::<i64>::identity();
-        let mut trans = na::Vector3::<i64>::zeros();
-        for from_to in path.windows(2) {
-            let from = from_to[0];
-            let to = from_to[1];
-            let (mut edge_rot, mut edge_trans) = graph.edge_weight(from, to).unwrap().clone();
-            if from < to {
-                edge_rot = edge_rot.transpose();
-                edge_trans = -edge_rot * edge_trans;
-            }
-            // compose
-            let new_rot = edge_rot * rot;
-            let new_trans = edge_rot * trans + edge_trans;
-            rot = new_rot;
-            trans = new_trans;
-        }
-        for pt in scanners[i].iter() {
-            let transformed = rot * pt + trans;
-            pts_in_zero_frame.insert(transformed);
-        }
+        graph.add_alignment(i, j, r, t);
     }
-    for pt in scanners[0].iter() {
-        pts_in_zero_frame.insert(*pt);
-    }
-    eprintln!("p1: {}", pts_in_zero_frame.len());
-
-    // p2
-    let mut max_manhatten = 0;
-    for i in 0..max_scanner {
-        for j in (i + 1)..max_scanner {
-            let paths =
-                petgraph::algo::simple_paths::all_simple_paths::<Vec<_>, _>(&graph, i, j, 0, None)
-                    .collect::<Vec<_>>();
-            let path = &paths[0];
-            let mut rot = na::Matrix3::<i64>::identity();
-            let mut trans = na::Vector3::<i64>::zeros();
-            for from_to in path.windows(2) {
-                let from = from_to[0];
-                let to = from_to[1];
-                let (mut edge_rot, mut edge_trans) = graph.edge_weight(from, to).unwrap().clone();
-                if from < to {
-                    edge_rot = edge_rot.transpose();
-                    edge_trans = -edge_rot * edge_trans;
+    let poses = graph.poses_from(0, scanners.len());
+
+    // Keep the first sighting of each beacon
+    let mut seen = BeaconIndex::new(config.tolerance);
+    let mut beacons = vec![];
+    for (scan, pose) in scanners.iter().zip(poses.iter()) {
+        if let Some(pose) = pose {
+            for pt in scan.iter().map(|pt| pose.apply(pt)) {
+                if seen.find(&pt).is_none() {
+                    seen.insert(pt);
+                    beacons.push(pt);
                 }
-                // compose
-                let new_rot = edge_rot * rot;
-                let new_trans = edge_rot * trans + edge_trans;
-                rot = new_rot;
-                trans = new_trans;
             }
-            let manhatten = trans.abs().sum();
-            max_manhatten = max_manhatten.max(manhatten);
         }
     }
-    eprintln!("p2: {}", max_manhatten);
+    beacons.sort_by_key(|pt| (pt.x, pt.y, pt.z));
+
+    Reconstruction { poses, beacons }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        panic!("There should be one argument");
+    if args.len() < 2 {
+        panic!(
+            "usage: aoc_19 <input> [--min-overlap N] [--tolerance T] [--ransac ITERATIONS] \
+             [export.ply|obj|csv]"
+        );
     }
+    let mut config = AlignmentConfig::default();
+    let mut export_path = None;
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        let mut value = || {
+            flags
+                .next()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or_else(|| panic!("{} needs a number", flag))
+        };
+        match flag.as_str() {
+            "--min-overlap" => {
+                // At least one inlier is needed to accept and refine an alignment
+                config.min_overlap = value();
+                assert!(
+                    config.min_overlap > 0,
+                    "--min-overlap needs a positive number"
+                );
+            }
+            "--tolerance" => config.tolerance = value() as i64,
+            "--ransac" => {
+                config.ransac = Some(RansacConfig {
+                    iterations: value(),
+                    seed: 2021,
+                })
+            }
+            other if other.starts_with("--") => panic!("unknown option {}", other),
+            path => export_path = Some(path),
+        }
+    }
+
     let file_str = std::fs::read_to_string(&args[1]).expect("couldn't read the file");
     let scanners = parse(&file_str);
-    p1_p2(&scanners);
+    let recon = reconstruct(&scanners, &config);
+    let disconnected = recon.disconnected();
+    if !disconnected.is_empty() {
+        eprintln!("scanners not aligned with scanner 0: {:?}", disconnected);
+    }
+    println!("p1: {}", recon.beacons.len());
+    println!("p2: {}", recon.max_scanner_distance());
+    if let Some(export_path) = export_path {
+        recon
+            .export(Path::new(export_path))
+            .expect("couldn't export the reconstruction");
+    }
 }
 
 #[cfg(test)]
@@ -337,14 +754,108 @@ mod test_day19 {
 -652,-548,-490
 30,-46,-14"#;
 
-        let scanners = parse(&example);
+        let scanners = parse(example);
+
+        {
+            let fp0 = Fingerprint::new(&scanners[0]);
+            let fp1 = Fingerprint::new(&scanners[1]);
+            let fp2 = Fingerprint::new(&scanners[2]);
+            let config = AlignmentConfig::default();
+            assert!(fp0.may_overlap(&fp1, &config));
+            assert!(!fp0.may_overlap(&fp2, &config));
+            let (_, t) =
+                attempt_pair_alignment(&scanners[0], &fp0, &scanners[1], &fp1, &config).unwrap();
+            assert_eq!(t, Vector3::new(68, -1246, -43));
+        }
+        {
+            let recon = reconstruct(&scanners, &AlignmentConfig::default());
+            assert_eq!(recon.beacons.len(), 79);
+            assert_eq!(recon.max_scanner_distance(), 3621);
+            assert_eq!(
+                recon.poses[2].unwrap().trans,
+                Vector3::new(1105, -1205, 1229)
+            );
+            assert_eq!(
+                recon.poses[4].unwrap().trans,
+                Vector3::new(-20, -1133, 1061)
+            );
+            assert!(recon.beacons.contains(&Point3::new(-892, 524, 684)));
+
+            let mut with_stray = scanners.clone();
+            with_stray.push(vec![Point3::new(0, 0, 0), Point3::new(1, 2, 3)]);
+            let recon = reconstruct(&with_stray, &AlignmentConfig::default());
+            assert_eq!(recon.disconnected(), vec![5]);
+            assert_eq!(recon.beacons.len(), 79);
+            // The stray scanner has no pose, so it gets no vertex either
+            let mut ply = Vec::new();
+            recon.write_ply(&mut ply).unwrap();
+            let ply = String::from_utf8(ply).unwrap();
+            assert!(ply.contains("element vertex 84\n"));
+            assert_eq!(ply.lines().count(), 10 + 84);
+
+            let recon = reconstruct(&scanners, &AlignmentConfig::default());
+            let mut csv = Vec::new();
+            recon.write_csv(&mut csv).unwrap();
+            let csv = String::from_utf8(csv).unwrap();
+            assert_eq!(csv.lines().nth(1), Some("beacon,-892,524,684"));
+            assert_eq!(csv.lines().last(), Some("scanner,-20,-1133,1061"));
+
+            let mut obj = Vec::new();
+            recon.write_obj(&mut obj).unwrap();
+            let obj = String::from_utf8(obj).unwrap();
+            let lines = obj.lines().collect::<Vec<_>>();
+            assert_eq!(lines[0], "o beacons");
+            assert_eq!(lines[1 + 79], "o scanners");
+            assert_eq!(lines.iter().filter(|l| l.starts_with("v ")).count(), 84);
+            assert_eq!(lines.len(), 2 + 84);
+            assert_eq!(lines.last(), Some(&"v -20 -1133 1061"));
+        }
 
         {
-            let scan0 = &scanners[0];
-            eprintln!("scan0: {:?}", scan0);
-            let scan1 = &scanners[1];
-            eprintln!("scan1: {:?}", scan1);
-            p1_p2(&scanners);
+            // Jitter every beacon by up to 1 on each axis, drop a few and add
+            // some that no other scanner sees
+            let noisy = scanners
+                .iter()
+                .enumerate()
+                .map(|(s, scan)| {
+                    let mut pts = scan
+                        .iter()
+                        .enumerate()
+                        .filter(|(k, _)| k % 13 != 12)
+                        .map(|(k, pt)| {
+                            let jitter = |m: usize| ((k * m + s * 5) % 3) as i64 - 1;
+                            pt + Vector3::new(jitter(7), jitter(11), jitter(13))
+                        })
+                        .collect::<Vec<_>>();
+                    pts.push(Point3::new(2000 + s as i64 * 10, 0, 0));
+                    pts.push(Point3::new(0, -2000, s as i64 * 10));
+                    pts
+                })
+                .collect::<Vec<_>>();
+
+            let strict = reconstruct(&noisy, &AlignmentConfig::default());
+            assert_eq!(strict.disconnected(), vec![1, 2, 3, 4]);
+
+            let config = AlignmentConfig {
+                min_overlap: 10,
+                tolerance: 4,
+                ransac: Some(RansacConfig {
+                    iterations: 300,
+                    seed: 19,
+                }),
+            };
+            let recon = reconstruct(&noisy, &config);
+            assert!(recon.disconnected().is_empty());
+            let truth = [
+                Vector3::new(0, 0, 0),
+                Vector3::new(68, -1246, -43),
+                Vector3::new(1105, -1205, 1229),
+                Vector3::new(-92, -2380, -20),
+                Vector3::new(-20, -1133, 1061),
+            ];
+            for (pose, truth) in recon.poses.iter().zip(truth.iter()) {
+                assert!(within(&pose.unwrap().trans, truth, 4));
+            }
         }
     }
 }
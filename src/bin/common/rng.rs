@@ -0,0 +1,28 @@
+//! xorshift64 generator for the bins that need repeatable pseudo-random numbers. Pull it in with
+//! `#[path = "common/rng.rs"] mod rng;` at the top level of the bin.
+
+// Each bin only uses part of this
+#![allow(dead_code)]
+
+pub struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    /// xorshift needs a non-zero state, so a zero seed is bumped to 1
+    pub fn new(seed: u64) -> Self {
+        XorShift64 { state: seed.max(1) }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number in `0..n`, with the slight bias of a plain modulo
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}